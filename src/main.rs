@@ -6,41 +6,27 @@ use rayon::prelude::*;
 #[allow(unused)]
 use std::io::Write;
 
-fn ray_color(r: &Ray, world: &dyn Shape, depth: i64, background: Vec3) -> Vec3 {
-    if depth <= 0 {
-        return Vec3::new(0.0, 0.0, 0.0);
-    }
-    let hit_info = world.hit(&r, EPS, f64::MAX);
-    if let Some(hit) = hit_info {
-        let emitted = hit.m.emitted(&r, &hit);
-        let scatter_info = hit.m.scatter(r, &hit);
-        if let Some(scatter) = scatter_info {
-            emitted
-                + scatter
-                    .albedo
-                    .mult(ray_color(&scatter.ray, world, depth - 1, background))
-        } else {
-            return emitted;
-        }
-    } else {
-        return background;
-    }
+//指定の誤りなどで描画を続けられないときは、理由を表示して失敗の終了コードで終える
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
 }
 
 fn main() {
-    let args = parameters();
+    let started = std::time::Instant::now();
+    let mut args = parameters();
     println!("{:?}", args);
+    //シーンの生成より先に決める
+    set_seed(args.seed);
     println!("sampling(use subpixel)={:?}",args.s*4);
 
     let mut w: usize = args.w;
     let mut h: usize = ((w as f64) / WIDE_ASPECT) as usize;
     let samps: usize = args.s;
 
-
-    let MAX_DEPTH: i64 = 32;
-
     let mut world = ShapeList::new();
     let mut background =Vec3::new(0.7,0.8,1.0);
+    let mut settings = SceneSettings::default();
     //オリジナルはRayの関数だがとりあえず定数で
     let cam: Camera;
     match args.m {
@@ -54,6 +40,8 @@ fn main() {
             cam = world.simple_scene();
 	    }
         2 => {
+            //ガラス球の中で何度も屈折するので深めにたどる
+            settings = settings.with_max_depth(50);
             cam = world.random_scene();
         }
         3 => {
@@ -79,36 +67,302 @@ fn main() {
             cam = world.cornell_scene();
             background=Vec3::zero();
         }
+        8 => {
+            cam = world.bump_scene();
+        }
+        9 => {
+            cam = world.cutout_scene();
+        }
+        10 => {
+            background = Vec3::new(0.02, 0.02, 0.03);
+            //点光源の周りが白く飛ばないように
+            settings = settings.with_pipeline(ColorPipeline::default().with_tonemap(ToneMapper::Aces));
+            cam = world.punctual_scene();
+        }
+        11 => {
+            w = args.w;
+            h = ((w as f64) / SQUARE_ASPECT) as usize;
+            cam = world.lamp_scene();
+            background = Vec3::zero();
+            //電球色と昼光色の中間を白にする
+            settings = settings.with_pipeline(
+                ColorPipeline::default().with_white_balance(Some(4000.0)).with_tonemap(ToneMapper::Agx),
+            );
+        }
         _ => {
             cam = world.simple_scene();
         }
     }
 
-    let mut image = vec![Color::zero(); (w * h) as usize];
-    let bands: Vec<(usize, &mut [Color])> = image.chunks_mut(w as usize).enumerate().collect();
-    bands.into_par_iter().for_each(|(y, band)| {
-        for x in 0..w {
-            let mut r = Vec3::new(0.0, 0.0, 0.0);
-            for _spp in 0..samps {
-                for _sy in 0..2 {
-                    for _sx in 0..2 {
-                        let u = (x as f64 + (_sx as f64 + random()) / 4.0) / (w as f64);
-                        let v = (y as f64 + (_sy as f64 + random()) / 4.0) / (h as f64);
-                        let ray = cam.get_ray(u, v);
-                        r = r + ray_color(&ray, &world, MAX_DEPTH, background)
-                            / (samps as f64)
-                            / 4.0;
-                    }
-                }
+    //コマンドラインで省略した描画設定はシーンの既定値にする
+    let max_depth = *args.max_depth.get_or_insert(settings.max_depth);
+    let pipeline = args.pipeline(settings.pipeline);
+
+    let env: Box<dyn EnvLight> = if let Some(path) = &args.envmap {
+        match EnvMap::open(path, args.env_rotation, args.env_intensity) {
+            Ok(map) => Box::new(map),
+            Err(e) => {
+                eprintln!("failed to load environment map {}: {}", path, e);
+                Box::new(ConstantEnv::new(background))
+            }
+        }
+    } else if let Some((elevation, azimuth)) = args.sun {
+        Box::new(
+            PhysicalSky::new(elevation, azimuth, args.turbidity, args.ground_albedo)
+                .with_intensity(args.env_intensity),
+        )
+    } else if args.sky {
+        Box::new(GradientSky::default())
+    } else {
+        Box::new(ConstantEnv::new(background))
+    };
+
+    //点光源とDiffuseLightを持つ形状をまとめて光源選択に使う
+    let mut lights = std::mem::take(&mut world.lights);
+    world.collect_lights(&Transform::identity(), &mut lights);
+    let light_count = lights.len();
+    let lights = LightSampler::new(lights, args.light_sampler);
+
+    let output = OutputOptions { half: args.half, pipeline };
+    //ブルームなどは表示用の効果なので、浮動小数点の出力には--post-floatのときだけかける
+    let bake_post = !ImageFormat::from_path(&args.output).is_float() || args.post_float;
+
+    //SPPMは反復ごとに画面全体を処理するので画素ごとのループを使わない サンプル数を反復回数にする
+    if args.integrator == IntegratorKind::Sppm {
+        let default_filter = Filter::new(FilterKind::Box);
+        let ignored: Vec<&str> = [
+            ("--aov", !args.aovs.is_empty()),
+            ("--denoise", args.denoise),
+            ("--filter", args.filter.kind != default_filter.kind || args.filter.radius != default_filter.radius),
+            ("--adaptive", args.adaptive.is_some()),
+            ("--spp-heatmap", args.spp_heatmap.is_some()),
+            ("--progressive", args.progressive),
+            ("--checkpoint", args.checkpoint.is_some()),
+            ("--resume", args.resume.is_some()),
+            ("--time-limit", args.time_limit.is_some()),
+            ("--target-noise", args.target_noise.is_some()),
+        ]
+        .into_iter()
+        .filter(|&(_, set)| set)
+        .map(|(name, _)| name)
+        .collect();
+        if !ignored.is_empty() {
+            eprintln!("{} ignored by the sppm integrator", ignored.join(", "));
+        }
+        let sppm = Sppm::new(&world, env.as_ref(), &lights, max_depth)
+            .with_photons(args.photons.unwrap_or(w * h))
+            .with_radius(args.photon_radius);
+        let mut image = sppm.render(&cam, w, h, samps);
+        if bake_post {
+            args.post.apply(&mut image, w, h);
+        }
+        save_image(&args.output, &image, w, h, &output);
+        return;
+    }
+
+    let film = Film::new(w, h, args.filter);
+    let integrator: Box<dyn Integrator + '_> = match args.integrator {
+        IntegratorKind::Path => Box::new(
+            PathTracer::new(&world, env.as_ref(), &lights)
+                .with_max_depth(max_depth)
+                .with_rr_depth(args.rr_depth),
+        ),
+        IntegratorKind::Bdpt => Box::new(Bdpt::new(&world, &cam, env.as_ref(), &lights, max_depth, &film)),
+        IntegratorKind::Direct => {
+            Box::new(DirectLighting::new(&world, env.as_ref(), &lights).with_max_depth(max_depth))
+        }
+        IntegratorKind::Ao => {
+            //既定の距離はシーンの対角線の1/10
+            let distance = args.ao_distance.unwrap_or_else(|| {
+                world.bounding_box().map_or(f64::MAX, |b| (b.max - b.min).length().sqrt() * 0.1)
+            });
+            Box::new(AmbientOcclusion::new(&world).with_distance(distance))
+        }
+        IntegratorKind::Debug(mode) => {
+            let view = DebugView::new(&world, mode);
+            if mode == DebugMode::Depth {
+                Box::new(view.with_scale(2.0 * cam.focus_dist()))
+            } else {
+                Box::new(view)
             }
-            band[x as usize] = r;
         }
-        if (y % 20) == 0 {
-            print!("y={0}  :", y);
-            println!("col={:?}", band[0]);
-        };
+        IntegratorKind::Sppm => unreachable!(),
+    };
+    //光の内訳を振り分けない積分器ではdirect/indirect/emissionが真っ黒になるので書かない
+    if !integrator.splits_light() && args.aovs.iter().any(|a| a.is_light_split()) {
+        let names: Vec<&str> = args.aovs.iter().filter(|a| a.is_light_split()).map(|a| a.name()).collect();
+        eprintln!("--aov {} is only written by the path integrator; skipped", names.join(","));
+        args.aovs.retain(|a| !a.is_light_split());
+    }
+
+    let spread = cam.pixel_spread(h);
+    //画素あたりのサンプル数 以前の2x2のジッタの分も含めてサンプラーに任せる
+    let spp = samps * 4;
+    let sampler = args.sampler.build(spp, args.seed);
+    //プログレッシブ描画では画面全体をpass_spp個ずつのパスで重ねて、途中経過を書き出す
+    //チェックポイントもパスの区切りで書くので同じようにパスに分ける
+    let checkpoint_path = args.checkpoint.as_ref().or(args.resume.as_ref());
+    //--time-limitか--target-noiseがあれば-sの数で止めずに、予算を使い切るまでパスを重ねる
+    let mut budget = RenderBudget::new(started, args.time_limit, args.target_noise);
+    let budgeted = budget.is_set();
+    let passes = args.progressive || checkpoint_path.is_some() || budgeted;
+    let pass_spp = if passes { args.pass_spp.clamp(1, spp) } else { spp };
+    let mut snapshots = args.progressive.then(|| Snapshots::new(args.snapshot_interval, args.snapshot_passes));
+    let mut checkpoints = checkpoint_path.map(|_| Snapshots::new(Some(args.checkpoint_interval), None));
+    //適応的サンプリングではspp個打った後も、誤差の大きい画素だけ次のパスで打つ
+    let adaptive = args.adaptive.map(|threshold| AdaptiveSampling {
+        threshold,
+        min_spp: spp,
+        max_spp: args.max_spp.unwrap_or(spp * 8).max(spp),
     });
+    //AOVかデノイズを頼まれたときだけ画素ごとのバッファを持つ AOVはフィルタをかけず画素内で平均する
+    let use_aov = !args.aovs.is_empty() || args.denoise;
+    let mut aov_sums = if use_aov { vec![AovSample::zero(); w * h] } else { Vec::new() };
+    let mut stats = vec![PixelVariance::default(); w * h];
+    //終えたパスの数
+    let mut pass = 0;
+
+    //設定とシーンが同じときだけ続きから描ける
+    let settings = settings_hash(&args.render_settings(pass_spp));
+    let scene = if checkpoint_path.is_some() { scene_fingerprint(&world, env.as_ref(), &cam, light_count) } else { 0 };
+    if let Some(path) = &args.resume {
+        let ckpt = Checkpoint::load(path).unwrap_or_else(|e| fail(format!("cannot read checkpoint {}: {}", path, e)));
+        if ckpt.width != w || ckpt.height != h || ckpt.settings != settings {
+            fail(format!("checkpoint {} was made with different render settings", path));
+        }
+        if ckpt.scene != scene {
+            fail(format!("checkpoint {} was made for a different scene", path));
+        }
+        film.restore(&ckpt.film).unwrap_or_else(|e| fail(format!("cannot restore checkpoint {}: {}", path, e)));
+        stats = ckpt.stats;
+        if use_aov {
+            aov_sums = ckpt.aovs;
+        }
+        pass = ckpt.pass;
+        println!("resumed {} after pass {}", path, pass);
+    }
+
+    //ここまでの結果を画像にして保存する 途中経過の書き出しと最後の保存で同じ処理を通す
+    let develop = |stats: &[PixelVariance], aov_sums: &[AovSample]| {
+        let counts: Vec<usize> = stats.iter().map(|s| s.n).collect();
+        let aov_image: Vec<AovSample> = aov_sums
+            .iter()
+            .zip(&counts)
+            .map(|(sum, &n)| {
+                let mut aov = AovSample::zero();
+                aov.accumulate(sum, 1.0 / n.max(1) as f64);
+                aov
+            })
+            .collect();
+        //光源側の経路から直接写った分(splat)はどの画素にもカメラサンプルの総数と同じ本数の経路が届くので、
+        //画素あたりの平均サンプル数で割って足す
+        let mean_spp = counts.iter().sum::<usize>() as f64 / (w * h) as f64;
+        let mut image = film.resolve(1.0 / mean_spp);
+        if args.denoise {
+            image = Denoiser::default().denoise(&image, &aov_image, w, h, &counts);
+        }
+        if bake_post {
+            args.post.apply(&mut image, w, h);
+        }
+        //    save_ppm_file("image.ppm", image, w, h);
+        save_render(&args.output, &image, &args.aovs, &aov_image, w, h, &output);
+        (counts, mean_spp)
+    };
 
-    //    save_ppm_file("image.ppm", image, w, h);
-    save_png_file(&args.output, image, w, h);
+    let active_pixels = |stats: &[PixelVariance]| match &adaptive {
+        Some(a) => a.active_pixels(stats, w, h),
+        None if budgeted => vec![true; stats.len()],
+        None => stats.iter().map(|s| s.n < spp).collect(),
+    };
+    //pass_sppで割り切れなくても-sや--max-sppを超えないよう、最後のパスは残りの数だけ打つ
+    let sample_limit = match &adaptive {
+        Some(a) => a.max_spp,
+        None if budgeted => usize::MAX,
+        None => spp,
+    };
+    let mut active = active_pixels(&stats);
+    while active.contains(&true) {
+        budget.begin_pass();
+        let aov_rows = aov_sums.chunks_mut(w).map(Some).chain(std::iter::repeat_with(|| None));
+        let bands: Vec<_> = stats.chunks_mut(w).zip(aov_rows).zip(active.chunks(w)).enumerate().collect();
+        bands.into_par_iter().for_each(|(y, ((stat_band, mut aov_band), active_band))| {
+            for x in (0..w).filter(|&x| active_band[x]) {
+                let stats = &mut stat_band[x];
+                for _ in 0..pass_spp.min(sample_limit.saturating_sub(stats.n)) {
+                    //フィルムに0,1次元、レンズに2,3次元を使う
+                    start_pixel_sample(&sampler, (x, y), stats.n);
+                    let (du, dv) = random_2d();
+                    let u = (x as f64 + du) / (w as f64);
+                    let v = (y as f64 + dv) / (h as f64);
+                    let ray = cam.get_ray(u, v).with_cone(0.0, spread);
+                    let c = match aov_band.as_deref_mut() {
+                        Some(aovs) => {
+                            let mut aov = AovSample::primary(&world, &ray);
+                            let c = integrator.li_aov(&ray, &mut aov);
+                            aov.set_radiance(c);
+                            aovs[x].accumulate(&aov, 1.0);
+                            c
+                        }
+                        None => integrator.li(&ray),
+                    };
+                    film.add_sample(x as f64 + du, y as f64 + dv, c);
+                    stats.add(c.luminance());
+                }
+            }
+            end_pixel_sample();
+            if pass == 0 && (y % 20) == 0 {
+                print!("y={0}  :", y);
+                println!("col={:?}", film.get(0, y));
+            };
+        });
+        pass += 1;
+        active = active_pixels(&stats);
+        let remaining = active.iter().filter(|&&b| b).count();
+        if adaptive.is_some() {
+            println!("adaptive pass {}: {} pixels above threshold", pass, remaining);
+        }
+        if remaining == 0 {
+            break;
+        }
+        let stop = budget.end_pass(image_noise(&stats));
+        //予算で止めるときも最後のパスまでを書いておき、予算を増やして続きから描けるようにする
+        if let (Some(path), Some(checkpoints)) = (checkpoint_path, checkpoints.as_mut())
+            && (stop.is_some() || checkpoints.due(pass))
+        {
+            let ckpt = Checkpoint {
+                settings,
+                scene,
+                width: w,
+                height: h,
+                pass,
+                film: film.state(),
+                stats: stats.clone(),
+                aovs: aov_sums.clone(),
+            };
+            ckpt.save(path).unwrap_or_else(|e| fail(format!("cannot write checkpoint {}: {}", path, e)));
+            println!("checkpoint after pass {} -> {}", pass, path);
+        }
+        if let Some(reason) = stop {
+            println!("stopped after pass {}: {}", pass, reason);
+            break;
+        }
+        if let Some(snapshots) = snapshots.as_mut()
+            && snapshots.due(pass)
+        {
+            let (_, mean_spp) = develop(&stats, &aov_sums);
+            println!("snapshot after pass {} ({:.1} spp) -> {}", pass, mean_spp, args.output);
+        }
+    }
+
+    if let Some(warning) = film.warning() {
+        eprintln!("warning: {}", warning);
+    }
+    let (counts, mean_spp) = develop(&stats, &aov_sums);
+    if adaptive.is_some() || budgeted {
+        println!("{:.1} spp on average, noise {:.4}", mean_spp, image_noise(&stats));
+    }
+    if let Some(path) = &args.spp_heatmap {
+        save_sample_heatmap(path, &counts, w, h);
+        println!("sample heatmap -> {}", path);
+    }
 }
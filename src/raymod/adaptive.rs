@@ -0,0 +1,77 @@
+use crate::raymod::*;
+
+//画素ごとの輝度の平均と分散をWelfordの方法で逐次求める
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PixelVariance {
+    pub n: usize,
+    pub mean: f64,
+    //平均との差の2乗の和
+    pub m2: f64,
+}
+
+impl PixelVariance {
+    pub fn add(&mut self, x: f64) {
+        self.n += 1;
+        let d = x - self.mean;
+        self.mean += d / self.n as f64;
+        self.m2 += d * (x - self.mean);
+    }
+
+    //標本の分散
+    pub fn variance(&self) -> f64 {
+        if self.n < 2 { 0.0 } else { self.m2 / (self.n - 1) as f64 }
+    }
+
+    //平均値の標準誤差を平均で割った相対誤差
+    //暗い画素で割り算が発散しないよう分母に少し足す 2サンプル未満では分散が分からないので無限大
+    pub fn relative_error(&self) -> f64 {
+        if self.n < 2 {
+            return f64::INFINITY;
+        }
+        (self.variance() / self.n as f64).sqrt() / (self.mean.abs() + 1e-3)
+    }
+}
+
+//画像全体の誤差の目安 画素ごとの相対誤差の2乗平均の平方根
+pub fn image_noise(stats: &[PixelVariance]) -> f64 {
+    let sum: f64 = stats.iter().map(|s| s.relative_error().powi(2)).sum();
+    (sum / stats.len().max(1) as f64).sqrt()
+}
+
+//誤差が大きい画素だけ追加でサンプルを打つ
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptiveSampling {
+    //この相対誤差を下回ったら打ち切る
+    pub threshold: f64,
+    //これだけ打つまでは誤差を見ない
+    pub min_spp: usize,
+    //画素あたりの上限
+    pub max_spp: usize,
+}
+
+impl AdaptiveSampling {
+    //次のパスでも打つ画素 一画素の分散の見積もりはぶれるので、周囲3x3の最大の誤差で判定する
+    pub fn active_pixels(&self, stats: &[PixelVariance], width: usize, height: usize) -> Vec<bool> {
+        (0..width * height)
+            .map(|i| {
+                if stats[i].n >= self.max_spp {
+                    return false;
+                }
+                if stats[i].n < self.min_spp {
+                    return true;
+                }
+                let (x, y) = (i % width, i / width);
+                let ys = y.saturating_sub(1)..=(y + 1).min(height - 1);
+                ys.flat_map(|qy| (x.saturating_sub(1)..=(x + 1).min(width - 1)).map(move |qx| qy * width + qx))
+                    .any(|q| stats[q].relative_error() > self.threshold)
+            })
+            .collect()
+    }
+}
+
+//画素ごとのサンプル数を上限に対する割合で青→赤に塗って保存する
+pub fn save_sample_heatmap(filename: &str, counts: &[usize], width: usize, height: usize) {
+    let max = counts.iter().copied().max().unwrap_or(1).max(1);
+    let image: Vec<Color> = counts.iter().map(|&n| heat_color(n as f64 / max as f64)).collect();
+    save_png_raw(filename, &image, width, height);
+}
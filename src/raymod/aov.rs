@@ -0,0 +1,201 @@
+use crate::raymod::*;
+
+use std::path::Path;
+use std::str::FromStr;
+
+//合成用に本画像と別に書き出す画素ごとの値(Arbitrary Output Variables)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aov {
+    //最初の交点の反射率
+    Albedo,
+    //最初の交点のシェーディング法線
+    Normal,
+    //カメラから最初の交点までの距離
+    Depth,
+    //最初に当たったシーン直下の物体の番号
+    ObjectId,
+    //一回反射して届いた光
+    Direct,
+    //二回以上反射して届いた光
+    Indirect,
+    //光源や背景が直接見えている分
+    Emission,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 7] = [
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::ObjectId,
+        Aov::Direct,
+        Aov::Indirect,
+        Aov::Emission,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::ObjectId => "id",
+            Aov::Direct => "direct",
+            Aov::Indirect => "indirect",
+            Aov::Emission => "emission",
+        }
+    }
+
+    //光の内訳 積分器が振り分けたときだけ値が入る
+    pub fn is_light_split(&self) -> bool {
+        matches!(self, Aov::Direct | Aov::Indirect | Aov::Emission)
+    }
+
+    //カンマ区切りの一覧 allならすべて
+    pub fn parse_list(s: &str) -> Result<Vec<Aov>, String> {
+        if s == "all" {
+            return Ok(Aov::ALL.to_vec());
+        }
+        s.split(',').map(|x| x.trim().parse()).collect()
+    }
+}
+
+impl FromStr for Aov {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Aov::ALL
+            .iter()
+            .find(|a| a.name() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown aov: {} (albedo|normal|depth|id|direct|indirect|emission|all)", s))
+    }
+}
+
+//一画素分のAOV サンプルごとに作って足し合わせる
+#[derive(Debug, Clone, Copy)]
+pub struct AovSample {
+    pub albedo: Color,
+    pub normal: Vec3,
+    pub depth: f64,
+    //平均すると意味がないので最初のサンプルの値を使う 何にも当たらなければ-1
+    pub object_id: f64,
+    pub direct: Color,
+    pub indirect: Color,
+    pub emission: Color,
+    //本画像の輝度とその2乗の平均 デノイザが分散を見積もるのに使う
+    pub lum: f64,
+    pub lum2: f64,
+}
+
+impl AovSample {
+    pub fn zero() -> Self {
+        Self {
+            albedo: Color::zero(),
+            normal: Vec3::zero(),
+            depth: 0.0,
+            object_id: -1.0,
+            direct: Color::zero(),
+            indirect: Color::zero(),
+            emission: Color::zero(),
+            lum: 0.0,
+            lum2: 0.0,
+        }
+    }
+
+    //カメラレイの最初の交点から形状の情報を取る
+    pub fn primary(world: &ShapeList, ray: &Ray) -> Self {
+        let mut aov = Self::zero();
+        if let Some((id, mut hit)) = world.hit_object(ray, EPS, f64::MAX) {
+            hit.footprint = ray.cone_width(hit.t);
+            aov.albedo = hit.m.albedo(&hit);
+            aov.normal = hit.m.normal_at(&hit);
+            aov.depth = hit.t * ray.d.length().sqrt();
+            aov.object_id = id as f64;
+        }
+        aov
+    }
+
+    //光が届くまでに反射した回数で振り分ける
+    pub fn add_light(&mut self, bounces: i64, c: Color) {
+        match bounces {
+            0 => self.emission = self.emission + c,
+            1 => self.direct = self.direct + c,
+            _ => self.indirect = self.indirect + c,
+        }
+    }
+
+    //重みwを掛けて足し込む object_idは最初の値を残す
+    pub fn accumulate(&mut self, other: &AovSample, w: f64) {
+        self.albedo = self.albedo + other.albedo * w;
+        self.normal = self.normal + other.normal * w;
+        self.depth += other.depth * w;
+        if self.object_id < 0.0 {
+            self.object_id = other.object_id;
+        }
+        self.direct = self.direct + other.direct * w;
+        self.indirect = self.indirect + other.indirect * w;
+        self.emission = self.emission + other.emission * w;
+        self.lum += other.lum * w;
+        self.lum2 += other.lum2 * w;
+    }
+
+    //このサンプルの本画像の値を分散の見積もり用に記録する
+    pub fn set_radiance(&mut self, c: Color) {
+        self.lum = c.luminance();
+        self.lum2 = self.lum * self.lum;
+    }
+
+    pub fn get(&self, aov: Aov) -> Color {
+        match aov {
+            Aov::Albedo => self.albedo,
+            Aov::Normal => self.normal,
+            Aov::Depth => Color::new(self.depth, self.depth, self.depth),
+            Aov::ObjectId => Color::new(self.object_id, self.object_id, self.object_id),
+            Aov::Direct => self.direct,
+            Aov::Indirect => self.indirect,
+            Aov::Emission => self.emission,
+        }
+    }
+}
+
+//本画像image.pngに対してimage_albedo.pngのような名前にする
+pub fn aov_path(output: &str, aov: Aov) -> String {
+    let path = Path::new(output);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("image");
+    let name = match path.extension().and_then(|s| s.to_str()) {
+        Some(ext) => format!("{}_{}.{}", stem, aov.name(), ext),
+        None => format!("{}_{}", stem, aov.name()),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+//AOVを一枚ずつ書き出す 浮動小数点の形式なら値をそのまま、8bit画像なら法線・深度・番号を見える範囲に直す
+pub fn save_aovs(output: &str, aovs: &[Aov], pixels: &[AovSample], width: usize, height: usize, opts: &OutputOptions) {
+    let float = ImageFormat::from_path(output).is_float();
+    for &aov in aovs {
+        let path = aov_path(output, aov);
+        let image: Vec<Color> = pixels.iter().map(|p| p.get(aov)).collect();
+        match aov {
+            _ if float => save_image(&path, &image, width, height, opts),
+            Aov::Normal => {
+                let image: Vec<Color> = image.iter().map(|&n| (n + Vec3::new(1.0, 1.0, 1.0)) * 0.5).collect();
+                save_png_raw(&path, &image, width, height);
+            }
+            Aov::Depth => {
+                let far = image.iter().map(|c| c.x).fold(0.0, f64::max).max(EPS);
+                let image: Vec<Color> = image.iter().map(|&c| c / far).collect();
+                save_png_raw(&path, &image, width, height);
+            }
+            Aov::ObjectId => {
+                let image: Vec<Color> = image
+                    .iter()
+                    .map(|c| if c.x < 0.0 { Color::zero() } else { hash_color(c.x as u64) })
+                    .collect();
+                save_png_raw(&path, &image, width, height);
+            }
+            Aov::Albedo => save_png_file(&path, image, width, height),
+            //光の内訳は本画像と同じ色の処理をかける
+            Aov::Direct | Aov::Indirect | Aov::Emission => save_image(&path, &image, width, height, opts),
+        }
+        println!("aov {} -> {}", aov.name(), path);
+    }
+}
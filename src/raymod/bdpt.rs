@@ -0,0 +1,449 @@
+use crate::raymod::*;
+
+//双方向パストレーシング(Veach 1997, PBRT 3版16章)
+//点光源・スポットライト・面光源は光源側の経路とすべての接続方法をMISで重み付けする
+//環境光と平行光源は無限遠にあって光源側の経路を作れないので、カメラ側の経路から次イベント推定で足す
+//Dielectricの屈折による放射輝度のη^2倍やシェーディング法線の非対称性は考えない
+
+#[derive(Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+//経路の頂点 pdf_fwdは経路を作った向き、pdf_revは逆向きに作ったときの面積あたりの確率密度
+#[derive(Clone)]
+struct Vertex<'a> {
+    kind: VertexKind,
+    p: Vec3,
+    //面上の点の幾何法線 点光源やカメラはNone
+    n: Option<Vec3>,
+    //面上の点の交点情報とそこへ来たレイ 面光源の始点は交点情報だけ持つ
+    hit: Option<HitInfo>,
+    ray: Option<Ray>,
+    light: Option<&'a dyn Light>,
+    beta: Color,
+    //鏡面のように方向が一つに決まる散乱
+    delta: bool,
+    pdf_fwd: f64,
+    pdf_rev: f64,
+}
+
+impl<'a> Vertex<'a> {
+    fn camera(p: Vec3, beta: Color) -> Self {
+        Self {
+            kind: VertexKind::Camera,
+            p,
+            n: None,
+            hit: None,
+            ray: None,
+            light: None,
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn light(light: &'a dyn Light, le: &LeSample, beta: Color, pdf_fwd: f64) -> Self {
+        Self {
+            kind: VertexKind::Light,
+            p: le.ray.o,
+            n: le.n,
+            hit: le.hit.clone(),
+            ray: None,
+            light: Some(light),
+            beta,
+            delta: false,
+            pdf_fwd,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn surface(hit: HitInfo, ray: &Ray, beta: Color) -> Self {
+        Self {
+            kind: VertexKind::Surface,
+            p: hit.p,
+            n: Some(hit.n),
+            hit: Some(hit),
+            ray: Some(ray.clone()),
+            light: None,
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn is_connectible(&self) -> bool {
+        self.kind != VertexKind::Surface || !self.delta
+    }
+
+    fn is_delta_light(&self) -> bool {
+        self.light.is_some_and(|l| l.is_delta())
+    }
+
+    //立体角あたりの確率密度を次の頂点での面積あたりに直す
+    fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        let w = next.p - self.p;
+        let dist2 = w.length();
+        if dist2 == 0.0 {
+            return 0.0;
+        }
+        match next.n {
+            Some(n) => pdf * (n.dot(&w) / dist2.sqrt()).abs() / dist2,
+            None => pdf / dist2,
+        }
+    }
+
+    //この頂点からtoへ向かうBSDF×cos
+    fn f(&self, to: &Vertex) -> Color {
+        match (&self.hit, &self.ray) {
+            (Some(hit), Some(ray)) => hit.m.eval(ray, hit, (to.p - self.p).norm()),
+            _ => Color::zero(),
+        }
+    }
+
+    //この頂点(光源上の点)から点toへ向かう放射輝度 点光源は放射強度
+    fn le(&self, to: Vec3) -> Color {
+        if let Some(hit) = &self.hit {
+            return hit.m.emitted(&Ray::new(to, self.p - to), hit);
+        }
+        match self.light.and_then(|l| l.sample_li(to)) {
+            Some(ls) => ls.li * (ls.dist * ls.dist),
+            None => Color::zero(),
+        }
+    }
+}
+
+pub struct Bdpt<'a> {
+    world: &'a dyn Shape,
+    cam: &'a Camera,
+    env: &'a dyn EnvLight,
+    lights: &'a LightSampler,
+    max_depth: usize,
+    //t=1の寄与を画面上の位置に配る先
+    film: &'a Film,
+}
+
+impl<'a> Bdpt<'a> {
+    pub fn new(
+        world: &'a dyn Shape,
+        cam: &'a Camera,
+        env: &'a dyn EnvLight,
+        lights: &'a LightSampler,
+        max_depth: i64,
+        film: &'a Film,
+    ) -> Self {
+        Self {
+            world,
+            cam,
+            env,
+            lights,
+            max_depth: max_depth as usize,
+            film,
+        }
+    }
+
+    fn unoccluded(&self, a: Vec3, b: Vec3) -> bool {
+        self.world.hit(&Ray::new(a, b - a), EPS, 1.0 - EPS10).is_none()
+    }
+
+    //面光源の発光を光源側の経路の始点として扱ったときの光源
+    fn emitter(&self, v: &Vertex<'a>) -> Option<(&'a dyn Light, f64)> {
+        match v.kind {
+            VertexKind::Light => v.light.map(|l| (l, v.pdf_fwd)),
+            _ => self.lights.emitter_origin(v.hit.as_ref()?),
+        }
+    }
+
+    //頂点vが光源として点nextへ光を放つ確率密度(nextでの面積あたり)
+    fn pdf_light(&self, v: &Vertex, next: &Vertex) -> f64 {
+        let Some((light, _)) = self.emitter(v) else {
+            return 0.0;
+        };
+        let w = (next.p - v.p).norm();
+        let (_, pdf_dir) = light.pdf_le(v.n.unwrap_or(w), w);
+        v.convert_density(pdf_dir, next)
+    }
+
+    //頂点vが光源側の経路の始点に選ばれる確率密度
+    fn pdf_light_origin(&self, v: &Vertex) -> f64 {
+        match v.kind {
+            VertexKind::Light => v.pdf_fwd,
+            _ => self.emitter(v).map_or(0.0, |(_, pdf)| pdf),
+        }
+    }
+
+    //prevから来てvで散乱しnextへ向かう確率密度(nextでの面積あたり)
+    fn pdf(&self, v: &Vertex, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        match v.kind {
+            VertexKind::Light => self.pdf_light(v, next),
+            VertexKind::Camera => {
+                let (_, pdf_dir) = self.cam.pdf_we(v.p, (next.p - v.p).norm());
+                v.convert_density(pdf_dir, next)
+            }
+            VertexKind::Surface => {
+                let (Some(hit), Some(prev)) = (&v.hit, prev) else {
+                    return 0.0;
+                };
+                let ray = Ray::new(prev.p, v.p - prev.p);
+                let pdf = hit.m.pdf(&ray, hit, (next.p - v.p).norm());
+                v.convert_density(pdf, next)
+            }
+        }
+    }
+
+    //経路を伸ばす カメラ側なら環境光と平行光源の寄与を返す
+    fn random_walk(
+        &self,
+        mut ray: Ray,
+        mut beta: Color,
+        mut pdf_fwd: f64,
+        max_vertices: usize,
+        path: &mut Vec<Vertex<'a>>,
+        camera: bool,
+    ) -> Color {
+        let mut l = Color::zero();
+        //直前の散乱方向の確率密度 環境光のMIS用
+        let mut scatter_pdf = 0.0;
+        for _ in 0..max_vertices {
+            let Some(mut hit) = self.world.hit(&ray, EPS, f64::MAX) else {
+                if camera {
+                    let le = self.env.radiance(ray.d);
+                    let w = if scatter_pdf > 0.0 { power_heuristic(scatter_pdf, self.env.pdf(ray.d)) } else { 1.0 };
+                    l = l + beta.mult(le) * w;
+                }
+                break;
+            };
+            if camera {
+                hit.footprint = ray.cone_width(hit.t);
+            }
+            let mut v = Vertex::surface(hit.clone(), &ray, beta);
+            v.pdf_fwd = path.last().unwrap().convert_density(pdf_fwd, &v);
+            path.push(v);
+            let cur = path.len() - 1;
+            let Some(scatter) = hit.m.scatter(&ray, &hit) else {
+                break;
+            };
+            let wi = scatter.ray.d.norm();
+            let pdf_rev = if scatter.is_specular() {
+                path[cur].delta = true;
+                0.0
+            } else {
+                if camera {
+                    l = l + beta.mult(sample_env(&ray, &hit, self.world, self.env) + self.sample_infinite(&ray, &hit));
+                }
+                hit.m.pdf(&Ray::new(hit.p, -wi), &hit, -ray.d.norm())
+            };
+            beta = beta.mult(scatter.albedo);
+            if beta.is_black() {
+                break;
+            }
+            path[cur - 1].pdf_rev = path[cur].convert_density(pdf_rev, &path[cur - 1]);
+            pdf_fwd = scatter.pdf;
+            scatter_pdf = scatter.pdf;
+            ray = if camera { scatter.ray.with_cone(hit.footprint, ray.spread) } else { scatter.ray };
+        }
+        l
+    }
+
+    //平行光源の次イベント推定
+    fn sample_infinite(&self, r: &Ray, hit: &HitInfo) -> Color {
+        let mut l = Color::zero();
+        for light in self.lights.infinite_lights() {
+            if let Some(ls) = light.sample_li(hit.p) {
+                let f = hit.m.eval(r, hit, ls.wi);
+                if !f.is_black() && self.world.hit(&Ray::new(hit.p, ls.wi), EPS, ls.dist * (1.0 - EPS10)).is_none() {
+                    l = l + f.mult(ls.li) / ls.pdf;
+                }
+            }
+        }
+        l
+    }
+
+    fn light_subpath(&self) -> Vec<Vertex<'a>> {
+        let mut path = Vec::new();
+        let Some((light, pmf)) = self.lights.sample_power(random()) else {
+            return path;
+        };
+        let Some(le) = light.sample_le() else {
+            return path;
+        };
+        if le.pdf_pos <= 0.0 || le.pdf_dir <= 0.0 || le.le.is_black() {
+            return path;
+        }
+        let origin_pdf = pmf * le.pdf_pos;
+        path.push(Vertex::light(light, &le, le.le / origin_pdf, origin_pdf));
+        let cos = le.n.map_or(1.0, |n| n.dot(&le.ray.d).abs());
+        let beta = le.le * (cos / (origin_pdf * le.pdf_dir));
+        self.random_walk(le.ray.clone(), beta, le.pdf_dir, self.max_depth, &mut path, false);
+        path
+    }
+
+    //s本の光源側の頂点とt本のカメラ側の頂点をつないだ経路のMIS重み(バランスヒューリスティック)
+    fn mis_weight(&self, light: &[Vertex], cam: &[Vertex], sampled: Option<&Vertex>, s: usize, t: usize) -> f64 {
+        if s + t == 2 {
+            return 1.0;
+        }
+        //確率密度だけを写して接続の両端を書き換える
+        let mut lp: Vec<(f64, f64, bool)> = light[..s].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+        let mut cp: Vec<(f64, f64, bool)> = cam[..t].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+        let qs = if s == 1 { sampled } else if s > 0 { Some(&light[s - 1]) } else { None };
+        let pt = if t == 1 { sampled.unwrap() } else { &cam[t - 1] };
+        let qs_minus = if s > 1 { Some(&light[s - 2]) } else { None };
+        let pt_minus = if t > 1 { Some(&cam[t - 2]) } else { None };
+        if s == 1 {
+            lp[0] = (qs.unwrap().pdf_fwd, 0.0, false);
+        }
+        let delta_light0 = if s == 1 { qs.unwrap().is_delta_light() } else { s > 0 && light[0].is_delta_light() };
+        cp[t - 1].2 = false;
+        if s > 0 {
+            lp[s - 1].2 = false;
+        }
+        cp[t - 1].1 = match qs {
+            Some(qs) => self.pdf(qs, qs_minus, pt),
+            None => self.pdf_light_origin(pt),
+        };
+        if let Some(pt_minus) = pt_minus {
+            cp[t - 2].1 = match qs {
+                Some(qs) => self.pdf(pt, Some(qs), pt_minus),
+                None => self.pdf_light(pt, pt_minus),
+            };
+        }
+        if let Some(qs) = qs {
+            lp[s - 1].1 = self.pdf(pt, pt_minus, qs);
+            if let Some(qs_minus) = qs_minus {
+                lp[s - 2].1 = self.pdf(qs, Some(pt), qs_minus);
+            }
+        }
+        let remap = |x: f64| if x != 0.0 { x } else { 1.0 };
+        let mut sum = 0.0;
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap(cp[i].1) / remap(cp[i].0);
+            if !cp[i].2 && !cp[i - 1].2 {
+                sum += ri;
+            }
+        }
+        ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap(lp[i].1) / remap(lp[i].0);
+            let delta_prev = if i > 0 { lp[i - 1].2 } else { delta_light0 };
+            if !lp[i].2 && !delta_prev {
+                sum += ri;
+            }
+        }
+        1.0 / (1.0 + sum)
+    }
+
+    //接続方法(s,t)の寄与 t=1ならフィルム上の位置も返す
+    fn connect(&self, light: &[Vertex<'a>], cam: &[Vertex<'a>], s: usize, t: usize) -> (Color, Option<(f64, f64)>) {
+        let mut sampled: Option<Vertex> = None;
+        let mut raster = None;
+        let l = if s == 0 {
+            //カメラ側の経路が光源に当たった
+            let pt = &cam[t - 1];
+            let (Some(hit), Some(ray)) = (&pt.hit, &pt.ray) else {
+                return (Color::zero(), None);
+            };
+            if !self.lights.is_sampled(hit) {
+                //光源として選べない発光体はこの方法でしか見つからない
+                return (pt.beta.mult(hit.m.emitted(ray, hit)), None);
+            }
+            pt.beta.mult(hit.m.emitted(ray, hit))
+        } else if t == 1 {
+            //光源側の頂点からレンズ上の点へつなぐ
+            let qs = &light[s - 1];
+            if !qs.is_connectible() {
+                return (Color::zero(), None);
+            }
+            let Some(cs) = self.cam.sample_wi(qs.p) else {
+                return (Color::zero(), None);
+            };
+            if cs.we <= 0.0 || cs.pdf <= 0.0 {
+                return (Color::zero(), None);
+            }
+            let v = Vertex::camera(cs.lens, Color::new(1.0, 1.0, 1.0) * (cs.we / cs.pdf));
+            let l = qs.beta.mult(qs.f(&v)).mult(v.beta);
+            if l.is_black() || !self.unoccluded(qs.p, cs.lens) {
+                return (Color::zero(), None);
+            }
+            raster = Some(cs.raster);
+            sampled = Some(v);
+            l
+        } else if s == 1 {
+            //カメラ側の頂点から新しく選んだ光源上の点へつなぐ
+            let pt = &cam[t - 1];
+            if !pt.is_connectible() {
+                return (Color::zero(), None);
+            }
+            let Some((light, pmf)) = self.lights.sample_power(random()) else {
+                return (Color::zero(), None);
+            };
+            let Some(le) = light.sample_le() else {
+                return (Color::zero(), None);
+            };
+            let origin_pdf = pmf * le.pdf_pos;
+            let v = Vertex::light(light, &le, Color::zero(), origin_pdf);
+            let d = v.p - pt.p;
+            let dist2 = d.length();
+            if origin_pdf <= 0.0 || dist2 == 0.0 {
+                return (Color::zero(), None);
+            }
+            let cos = v.n.map_or(1.0, |n| n.dot(&d).abs() / dist2.sqrt());
+            let l = pt.beta.mult(pt.f(&v)).mult(v.le(pt.p)) * (cos / (dist2 * origin_pdf));
+            if l.is_black() || !self.unoccluded(pt.p, v.p) {
+                return (Color::zero(), None);
+            }
+            sampled = Some(v);
+            l
+        } else {
+            let (qs, pt) = (&light[s - 1], &cam[t - 1]);
+            if !qs.is_connectible() || !pt.is_connectible() {
+                return (Color::zero(), None);
+            }
+            let dist2 = (qs.p - pt.p).length();
+            if dist2 == 0.0 {
+                return (Color::zero(), None);
+            }
+            let l = qs.beta.mult(qs.f(pt)).mult(pt.f(qs)).mult(pt.beta) / dist2;
+            if l.is_black() || !self.unoccluded(pt.p, qs.p) {
+                return (Color::zero(), None);
+            }
+            l
+        };
+        if l.is_black() {
+            return (Color::zero(), None);
+        }
+        (l * self.mis_weight(light, cam, sampled.as_ref(), s, t), raster)
+    }
+}
+
+impl Integrator for Bdpt<'_> {
+    //カメラレイ一本分の放射輝度 t=1の寄与はフィルムにsplatする
+    fn li(&self, r: &Ray) -> Color {
+        let mut cam = vec![Vertex::camera(r.o, Color::new(1.0, 1.0, 1.0))];
+        let (_, pdf_dir) = self.cam.pdf_we(r.o, r.d.norm());
+        let mut l = self.random_walk(r.clone(), Color::new(1.0, 1.0, 1.0), pdf_dir, self.max_depth + 1, &mut cam, true);
+        let light = self.light_subpath();
+        for t in 1..=cam.len() {
+            for s in 0..=light.len() {
+                let depth = s as i64 + t as i64 - 2;
+                if (s == 1 && t == 1) || depth < 0 || depth > self.max_depth as i64 {
+                    continue;
+                }
+                let (c, raster) = self.connect(&light, &cam, s, t);
+                if c.is_black() {
+                    continue;
+                }
+                match raster {
+                    Some((u, v)) => self.film.add_splat(u, v, c),
+                    None => l = l + c,
+                }
+            }
+        }
+        l
+    }
+}
@@ -1,7 +1,19 @@
 use crate::raymod::*;
 
+use std::cell::Cell;
 use std::cmp::Ordering;
 use std::f64;
+use std::sync::Arc;
+
+thread_local! {
+    //このスレッドで調べたBVHの節点の数 デバッグ表示用
+    static TRAVERSAL: Cell<u64> = const { Cell::new(0) };
+}
+
+//BVHの節点を調べた回数を0に戻し、それまでの値を返す
+pub fn take_traversal_count() -> u64 {
+    TRAVERSAL.with(|c| c.replace(0))
+}
 
 
 pub fn surrounding_box(box0: &AABB, box1: &AABB) -> AABB {
@@ -18,6 +30,7 @@ pub fn surrounding_box(box0: &AABB, box1: &AABB) -> AABB {
     AABB { min, max }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy)]
 pub struct AABB {
     pub min: Vec3,
@@ -51,6 +64,7 @@ enum BVHNode {
     Leaf(Box<dyn Shape>),
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct BVH {
     tree: BVHNode,
     bbox: AABB,
@@ -115,24 +129,45 @@ impl BVH {
 
 impl Shape for BVH {
     fn hit(&self, ray: &Ray, t_min: f64, mut t_max: f64) -> Option<HitInfo> {
-        if !self.bbox.hit(&ray, t_min, t_max) {
+        TRAVERSAL.with(|c| c.set(c.get() + 1));
+        if !self.bbox.hit(ray, t_min, t_max) {
             return None;
         }
         match &self.tree {
-            BVHNode::Leaf(leaf) => leaf.hit(&ray, t_min, t_max),
+            BVHNode::Leaf(leaf) => leaf.hit(ray, t_min, t_max),
             BVHNode::Branch { left, right } => {
-                let left = left.hit(&ray, t_min, t_max);
+                let left = left.hit(ray, t_min, t_max);
                 if let Some(l) = &left {
                     t_max = l.t
                 };
-                let right = right.hit(&ray, t_min, t_max);
+                let right = right.hit(ray, t_min, t_max);
                 if right.is_some() { right } else { left }
             }
         }
     }
 
     fn bounding_box(&self) -> Option<AABB> {
-        Some(self.bbox.clone())
+        Some(self.bbox)
+    }
+
+    fn collect_lights(&self, xf: &Transform, lights: &mut Vec<Box<dyn Light>>) {
+        match &self.tree {
+            BVHNode::Leaf(leaf) => leaf.collect_lights(xf, lights),
+            BVHNode::Branch { left, right } => {
+                left.collect_lights(xf, lights);
+                right.collect_lights(xf, lights);
+            }
+        }
+    }
+
+    fn collect_materials(&self, materials: &mut Vec<Arc<dyn Material>>) {
+        match &self.tree {
+            BVHNode::Leaf(leaf) => leaf.collect_materials(materials),
+            BVHNode::Branch { left, right } => {
+                left.collect_materials(materials);
+                right.collect_materials(materials);
+            }
+        }
     }
 }
 
@@ -0,0 +1,177 @@
+use crate::raymod::*;
+
+use std::fs;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+//描画途中の状態をファイルに残して、後から続きを描けるようにする
+//フィルムは固定小数点の整数、それ以外はf64のビット列をそのまま書くので、続きから描いても途中で止めなかった場合と同じ画像になる
+//乱数はシードと画素・サンプル番号から決まるので、シードを含む設定が同じなら状態を持たなくてよい
+
+//フィルムの固定小数点の桁を変えたら番号を上げる
+const MAGIC: &[u8; 8] = b"RRCKPT02";
+
+pub struct Checkpoint {
+    //描画結果に関わる設定とシーンのハッシュ 違えば続きを描けない
+    pub settings: u64,
+    pub scene: u64,
+    pub width: usize,
+    pub height: usize,
+    //終えたパスの数
+    pub pass: usize,
+    pub film: FilmState,
+    pub stats: Vec<PixelVariance>,
+    //AOVを集めていなければ空
+    pub aovs: Vec<AovSample>,
+}
+
+//設定の文字列のハッシュ
+pub fn settings_hash(settings: &str) -> u64 {
+    let words: Vec<u64> = settings
+        .as_bytes()
+        .chunks(8)
+        .map(|c| {
+            let mut b = [0u8; 8];
+            b[..c.len()].copy_from_slice(c);
+            u64::from_le_bytes(b)
+        })
+        .collect();
+    stable_hash(&words)
+}
+
+//シーンの指紋 カメラと、画面全体に格子状に飛ばしたレイの当たり方から作る
+//形状・材質・背景のどれかが変わっていれば値が変わる
+pub fn scene_fingerprint(world: &dyn Shape, env: &dyn EnvLight, cam: &Camera, light_count: usize) -> u64 {
+    const GRID: usize = 32;
+    //アルファテストの乱数が描画の乱数列とぶつからないよう専用の列を使う
+    start_stream(&[u64::MAX]);
+    let mut values = vec![light_count as u64, cam.lens_radius.to_bits()];
+    let push = |values: &mut Vec<u64>, v: Vec3| values.extend([v.x.to_bits(), v.y.to_bits(), v.z.to_bits()]);
+    push(&mut values, cam.origin);
+    push(&mut values, cam.upper_left_corner);
+    push(&mut values, cam.horizontal);
+    push(&mut values, cam.vertical);
+    for j in 0..GRID {
+        for i in 0..GRID {
+            let (s, t) = ((i as f64 + 0.5) / GRID as f64, (j as f64 + 0.5) / GRID as f64);
+            let ray = Ray::new(cam.origin, cam.upper_left_corner + s * cam.horizontal - t * cam.vertical - cam.origin);
+            match world.hit(&ray, EPS, f64::MAX) {
+                Some(hit) => {
+                    values.push(hit.t.to_bits());
+                    push(&mut values, hit.n);
+                    push(&mut values, hit.m.albedo(&hit));
+                    push(&mut values, hit.m.emitted(&ray, &hit));
+                }
+                None => push(&mut values, env.radiance(ray.d)),
+            }
+        }
+    }
+    stable_hash(&values)
+}
+
+fn put(w: &mut impl Write, v: u64) -> std::io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn put_f64(w: &mut impl Write, v: f64) -> std::io::Result<()> {
+    put(w, v.to_bits())
+}
+
+fn put_color(w: &mut impl Write, c: Color) -> std::io::Result<()> {
+    put_f64(w, c.x)?;
+    put_f64(w, c.y)?;
+    put_f64(w, c.z)
+}
+
+fn get(r: &mut impl Read) -> std::io::Result<u64> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+fn get_f64(r: &mut impl Read) -> std::io::Result<f64> {
+    get(r).map(f64::from_bits)
+}
+
+fn get_color(r: &mut impl Read) -> std::io::Result<Color> {
+    Ok(Color::new(get_f64(r)?, get_f64(r)?, get_f64(r)?))
+}
+
+impl Checkpoint {
+    //一時ファイルに書いてから置き換えるので、書いている途中で止まっても前の状態が残る
+    pub fn save(&self, filename: &str) -> std::io::Result<()> {
+        let tmp = format!("{}.tmp", filename);
+        {
+            let mut f = BufWriter::new(fs::File::create(&tmp)?);
+            f.write_all(MAGIC)?;
+            for v in [self.settings, self.scene, self.width as u64, self.height as u64, self.pass as u64] {
+                put(&mut f, v)?;
+            }
+            for list in [&self.film.pixels, &self.film.splats] {
+                put(&mut f, list.len() as u64)?;
+                for &v in list {
+                    put(&mut f, v as u64)?;
+                }
+            }
+            for s in &self.stats {
+                put(&mut f, s.n as u64)?;
+                put_f64(&mut f, s.mean)?;
+                put_f64(&mut f, s.m2)?;
+            }
+            put(&mut f, self.aovs.len() as u64)?;
+            for a in &self.aovs {
+                put_color(&mut f, a.albedo)?;
+                put_color(&mut f, a.normal)?;
+                put_f64(&mut f, a.depth)?;
+                put_f64(&mut f, a.object_id)?;
+                put_color(&mut f, a.direct)?;
+                put_color(&mut f, a.indirect)?;
+                put_color(&mut f, a.emission)?;
+                put_f64(&mut f, a.lum)?;
+                put_f64(&mut f, a.lum2)?;
+            }
+            f.flush()?;
+        }
+        fs::rename(&tmp, filename)
+    }
+
+    pub fn load(filename: &str) -> std::io::Result<Self> {
+        let mut f = BufReader::new(fs::File::open(filename)?);
+        let mut magic = [0u8; 8];
+        f.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "not a checkpoint file"));
+        }
+        let settings = get(&mut f)?;
+        let scene = get(&mut f)?;
+        let width = get(&mut f)? as usize;
+        let height = get(&mut f)? as usize;
+        let pass = get(&mut f)? as usize;
+        let mut lists = Vec::new();
+        for _ in 0..2 {
+            let len = get(&mut f)? as usize;
+            lists.push((0..len).map(|_| get(&mut f).map(|v| v as i64)).collect::<std::io::Result<Vec<i64>>>()?);
+        }
+        let splats = lists.pop().unwrap();
+        let pixels = lists.pop().unwrap();
+        let stats = (0..width * height)
+            .map(|_| Ok(PixelVariance { n: get(&mut f)? as usize, mean: get_f64(&mut f)?, m2: get_f64(&mut f)? }))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let aov_len = get(&mut f)? as usize;
+        let aovs = (0..aov_len)
+            .map(|_| {
+                Ok(AovSample {
+                    albedo: get_color(&mut f)?,
+                    normal: get_color(&mut f)?,
+                    depth: get_f64(&mut f)?,
+                    object_id: get_f64(&mut f)?,
+                    direct: get_color(&mut f)?,
+                    indirect: get_color(&mut f)?,
+                    emission: get_color(&mut f)?,
+                    lum: get_f64(&mut f)?,
+                    lum2: get_f64(&mut f)?,
+                })
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Ok(Self { settings, scene, width, height, pass, film: FilmState { pixels, splats }, stats, aovs })
+    }
+}
@@ -0,0 +1,156 @@
+use crate::raymod::*;
+
+use rayon::prelude::*;
+
+//エッジを避けるÀ-trousウェーブレットフィルタ(Dammertz 2010)に
+//SVGF(Schied 2017)の分散による輝度の重みを加えたもの
+//反射率で割った照明の成分をぼかし、最後に反射率を掛け戻してテクスチャを保つ
+
+//B3スプラインの係数
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+pub struct Denoiser {
+    //フィルタを掛ける回数 i回目は2^i画素おきに見る
+    iterations: usize,
+    //輝度の差を標準偏差の何倍まで許すか
+    sigma_luminance: f64,
+    //法線の内積を何乗するか
+    sigma_normal: f64,
+    //深度の差を距離に対する割合でどこまで許すか
+    sigma_depth: f64,
+    //反射率の差をどこまで許すか
+    sigma_albedo: f64,
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self {
+            iterations: 5,
+            sigma_luminance: 4.0,
+            sigma_normal: 128.0,
+            sigma_depth: 0.02,
+            sigma_albedo: 0.1,
+        }
+    }
+}
+
+//画素ごとの案内情報
+struct Guide {
+    normal: Vec3,
+    depth: f64,
+    albedo: Color,
+}
+
+impl Denoiser {
+    //samplesは画素ごとのサンプル数 AOVの輝度の2乗平均から平均値の分散を出す
+    pub fn denoise(&self, image: &[Color], aovs: &[AovSample], width: usize, height: usize, samples: &[usize]) -> Vec<Color> {
+        let guides: Vec<Guide> = aovs
+            .iter()
+            .map(|a| Guide {
+                normal: if a.normal.length() > 0.0 { a.normal.norm() } else { Vec3::zero() },
+                depth: a.depth,
+                albedo: a.albedo,
+            })
+            .collect();
+        //反射率のない面(光源など)や背景はそのまま残す
+        let demodulate = |c: f64, a: f64| if a > 0.01 { c / a } else { c };
+        let mut color: Vec<Color> = image
+            .iter()
+            .zip(&guides)
+            .map(|(c, g)| {
+                Color::new(demodulate(c.x, g.albedo.x), demodulate(c.y, g.albedo.y), demodulate(c.z, g.albedo.z))
+            })
+            .collect();
+        //分散も照明の成分に合わせて反射率の輝度の2乗で割る
+        let mut variance: Vec<f64> = aovs
+            .iter()
+            .zip(samples)
+            .map(|(a, &n)| {
+                let v = (a.lum2 - a.lum * a.lum).max(0.0) / n.max(1) as f64;
+                let al = a.albedo.luminance();
+                if al > 0.01 { v / (al * al) } else { v }
+            })
+            .collect();
+        for i in 0..self.iterations {
+            (color, variance) = self.step(&color, &variance, &guides, width, height, 1 << i);
+        }
+        color
+            .iter()
+            .zip(&guides)
+            .map(|(c, g)| {
+                let m = |a: f64| if a > 0.01 { a } else { 1.0 };
+                Color::new(c.x * m(g.albedo.x), c.y * m(g.albedo.y), c.z * m(g.albedo.z))
+            })
+            .collect()
+    }
+
+    //stride画素おきの5x5で一回ぼかす 分散は重みの2乗で伝える
+    fn step(
+        &self,
+        color: &[Color],
+        variance: &[f64],
+        guides: &[Guide],
+        width: usize,
+        height: usize,
+        stride: usize,
+    ) -> (Vec<Color>, Vec<f64>) {
+        //分散そのものは雑音が多いので3x3でならしてから使う
+        let blurred_variance = |x: usize, y: usize| {
+            let mut sum = 0.0;
+            let mut weight = 0.0;
+            for dy in -1i64..=1 {
+                for dx in -1i64..=1 {
+                    let (qx, qy) = (x as i64 + dx, y as i64 + dy);
+                    if qx < 0 || qy < 0 || qx >= width as i64 || qy >= height as i64 {
+                        continue;
+                    }
+                    let k = KERNEL[(dx + 2) as usize] * KERNEL[(dy + 2) as usize];
+                    sum += variance[qy as usize * width + qx as usize] * k;
+                    weight += k;
+                }
+            }
+            sum / weight
+        };
+        (0..width * height)
+            .into_par_iter()
+            .map(|p| {
+                let (x, y) = (p % width, p / width);
+                let gp = &guides[p];
+                if gp.depth <= 0.0 {
+                    return (color[p], variance[p]);
+                }
+                let lp = color[p].luminance();
+                let sigma_l = self.sigma_luminance * blurred_variance(x, y).sqrt() + EPS10;
+                let mut sum = Color::zero();
+                let mut sum_var = 0.0;
+                let mut weight = 0.0;
+                for (j, ky) in KERNEL.iter().enumerate() {
+                    for (i, kx) in KERNEL.iter().enumerate() {
+                        let qx = x as i64 + (i as i64 - 2) * stride as i64;
+                        let qy = y as i64 + (j as i64 - 2) * stride as i64;
+                        if qx < 0 || qy < 0 || qx >= width as i64 || qy >= height as i64 {
+                            continue;
+                        }
+                        let q = qy as usize * width + qx as usize;
+                        let gq = &guides[q];
+                        if gq.depth <= 0.0 {
+                            continue;
+                        }
+                        let w_n = gp.normal.dot(&gq.normal).max(0.0).powf(self.sigma_normal);
+                        let w_z = (-(gp.depth - gq.depth).abs() / (self.sigma_depth * gp.depth * stride as f64)).exp();
+                        let w_a = (-(gp.albedo - gq.albedo).length() / (self.sigma_albedo * self.sigma_albedo)).exp();
+                        let w_l = (-(lp - color[q].luminance()).abs() / sigma_l).exp();
+                        let w = kx * ky * w_n * w_z * w_a * w_l;
+                        sum = sum + color[q] * w;
+                        sum_var += variance[q] * w * w;
+                        weight += w;
+                    }
+                }
+                if weight <= 0.0 {
+                    return (color[p], variance[p]);
+                }
+                (sum / weight, sum_var / (weight * weight))
+            })
+            .unzip()
+    }
+}
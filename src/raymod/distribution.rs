@@ -0,0 +1,108 @@
+//区分定数な確率分布 環境マップや光源選択の重点サンプリングに使う
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64,
+}
+
+impl Distribution1D {
+    pub fn new(func: &[f64]) -> Self {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + func[i].max(0.0) / n as f64;
+        }
+        let integral = cdf[n];
+        if integral > 0.0 {
+            for c in cdf.iter_mut() {
+                *c /= integral;
+            }
+        } else {
+            //全部ゼロなら一様分布にしておく
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f64 / n as f64;
+            }
+        }
+        Self {
+            func: func.iter().map(|f| f.max(0.0)).collect(),
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f64 {
+        self.integral
+    }
+
+    //cdf[i] <= u < cdf[i+1] となるi
+    fn find(&self, u: f64) -> usize {
+        let i = self.cdf.partition_point(|&c| c <= u);
+        i.saturating_sub(1).min(self.count() - 1)
+    }
+
+    //[0,1)の連続値とその確率密度、区間番号を返す
+    pub fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+        let i = self.find(u);
+        let mut du = u - self.cdf[i];
+        let width = self.cdf[i + 1] - self.cdf[i];
+        if width > 0.0 {
+            du /= width;
+        }
+        let pdf = if self.integral > 0.0 { self.func[i] / self.integral } else { 1.0 };
+        ((i as f64 + du) / self.count() as f64, pdf, i)
+    }
+
+    //離散分布として区間番号とその確率を返す
+    pub fn sample_discrete(&self, u: f64) -> (usize, f64) {
+        let i = self.find(u);
+        (i, self.pdf_discrete(i))
+    }
+
+    pub fn pdf_discrete(&self, i: usize) -> f64 {
+        if self.integral > 0.0 {
+            self.func[i] / (self.integral * self.count() as f64)
+        } else {
+            1.0 / self.count() as f64
+        }
+    }
+}
+
+//2次元の区分定数分布 行(v)の周辺分布と各行の条件付き分布からなる
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    //funcはnu*nvの行優先配列
+    pub fn new(func: &[f64], nu: usize, nv: usize) -> Self {
+        let conditional: Vec<Distribution1D> =
+            (0..nv).map(|v| Distribution1D::new(&func[v * nu..(v + 1) * nu])).collect();
+        let marginal_func: Vec<f64> = conditional.iter().map(|d| d.integral()).collect();
+        let marginal = Distribution1D::new(&marginal_func);
+        Self { conditional, marginal }
+    }
+
+    //(u,v)とその確率密度を返す
+    pub fn sample(&self, u0: f64, u1: f64) -> ((f64, f64), f64) {
+        let (v, pdf_v, iv) = self.marginal.sample_continuous(u1);
+        let (u, pdf_u, _) = self.conditional[iv].sample_continuous(u0);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let nu = self.conditional[0].count();
+        let nv = self.marginal.count();
+        let iu = ((u * nu as f64) as usize).min(nu - 1);
+        let iv = ((v * nv as f64) as usize).min(nv - 1);
+        if self.marginal.integral() > 0.0 {
+            self.conditional[iv].func[iu] / self.marginal.integral()
+        } else {
+            1.0
+        }
+    }
+}
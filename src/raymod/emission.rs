@@ -0,0 +1,182 @@
+use crate::raymod::*;
+
+use std::f64::consts::PI;
+use std::fs;
+
+//CIE 1931 等色関数の区分ガウス近似(Wyman, Sloan, Shirley 2013)
+fn cie_xyz(lambda: f64) -> (f64, f64, f64) {
+    let g = |mu: f64, s1: f64, s2: f64| {
+        let s = if lambda < mu { s1 } else { s2 };
+        (-0.5 * ((lambda - mu) / s).powi(2)).exp()
+    };
+    let x = 1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2);
+    let y = 0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1);
+    let z = 1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8);
+    (x, y, z)
+}
+
+//プランクの法則 λはnm
+fn planck(lambda: f64, kelvin: f64) -> f64 {
+    const H: f64 = 6.62607015e-34;
+    const C: f64 = 2.99792458e8;
+    const KB: f64 = 1.380649e-23;
+    let l = lambda * 1e-9;
+    2.0 * H * C * C / (l.powi(5) * ((H * C / (l * KB * kelvin)).exp() - 1.0))
+}
+
+//黒体放射の色 輝度が1になるよう正規化したリニアsRGB
+pub fn blackbody(kelvin: f64) -> Color {
+    let (mut x, mut y, mut z) = (0.0, 0.0, 0.0);
+    let mut lambda = 380.0;
+    while lambda <= 780.0 {
+        let b = planck(lambda, kelvin);
+        let (cx, cy, cz) = cie_xyz(lambda);
+        x += b * cx;
+        y += b * cy;
+        z += b * cz;
+        lambda += 5.0;
+    }
+    let c = Color::new(
+        3.2406 * x - 1.5372 * y - 0.4986 * z,
+        -0.9689 * x + 1.8758 * y + 0.0415 * z,
+        0.0557 * x - 0.2040 * y + 1.0570 * z,
+    ) / y;
+    Color::new(c.x.max(0.0), c.y.max(0.0), c.z.max(0.0))
+}
+
+//IES LM-63 配光データ 最大光度が1になるよう正規化して形だけを使う
+pub struct IesProfile {
+    vertical: Vec<f64>,
+    horizontal: Vec<f64>,
+    //candela[h][v]
+    candela: Vec<Vec<f64>>,
+}
+
+impl IesProfile {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines();
+        let tilt = loop {
+            match lines.next() {
+                Some(line) if line.trim_start().starts_with("TILT=") => break line.trim().to_string(),
+                Some(_) => continue,
+                None => return Err("TILT= line not found".to_string()),
+            }
+        };
+        let rest: Vec<&str> = lines.collect();
+        let mut values = rest
+            .join(" ")
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<f64>().map_err(|e| format!("{}: {}", s, e)))
+            .collect::<Result<Vec<f64>, String>>()?
+            .into_iter();
+        let mut next = || values.next().ok_or_else(|| "unexpected end of IES data".to_string());
+        if tilt == "TILT=INCLUDE" {
+            //ランプの傾き補正は使わないので読み飛ばす
+            let _geometry = next()?;
+            let n = next()? as usize;
+            for _ in 0..2 * n {
+                next()?;
+            }
+        }
+        let _lamps = next()?;
+        let _lumens = next()?;
+        let multiplier = next()?;
+        let nv = next()? as usize;
+        let nh = next()? as usize;
+        let _photometric_type = next()?;
+        let _units = next()?;
+        for _ in 0..3 {
+            next()?; //width length height
+        }
+        for _ in 0..3 {
+            next()?; //ballast factor, future use, input watts
+        }
+        let vertical = (0..nv).map(|_| next()).collect::<Result<Vec<f64>, String>>()?;
+        let horizontal = (0..nh).map(|_| next()).collect::<Result<Vec<f64>, String>>()?;
+        let mut candela = Vec::with_capacity(nh);
+        for _ in 0..nh {
+            let row = (0..nv).map(|_| next().map(|c| c * multiplier)).collect::<Result<Vec<f64>, String>>()?;
+            candela.push(row);
+        }
+        let max = candela.iter().flatten().cloned().fold(0.0, f64::max);
+        if nv == 0 || nh == 0 || max <= 0.0 {
+            return Err("empty IES candela table".to_string());
+        }
+        for row in candela.iter_mut() {
+            for c in row.iter_mut() {
+                *c /= max;
+            }
+        }
+        Ok(Self { vertical, horizontal, candela })
+    }
+
+    //角度の表から区間と補間係数を探す
+    fn locate(table: &[f64], x: f64) -> (usize, usize, f64) {
+        if table.len() == 1 || x <= table[0] {
+            return (0, 0, 0.0);
+        }
+        let last = table.len() - 1;
+        if x >= table[last] {
+            return (last, last, 0.0);
+        }
+        let i = table.partition_point(|&a| a <= x) - 1;
+        let t = (x - table[i]) / (table[i + 1] - table[i]);
+        (i, i + 1, t)
+    }
+
+    //thetaは光軸(真下)からの角度、phiは水平角 どちらも度
+    pub fn eval(&self, theta: f64, phi: f64) -> f64 {
+        let h_max = *self.horizontal.last().unwrap();
+        //対称性に応じて水平角を折り返す
+        let mut phi = phi.rem_euclid(360.0);
+        if h_max <= 0.0 {
+            phi = 0.0;
+        } else if h_max <= 90.0 {
+            phi = if phi > 270.0 { 360.0 - phi } else if phi > 180.0 { phi - 180.0 } else { phi };
+            if phi > 90.0 {
+                phi = 180.0 - phi;
+            }
+        } else if h_max <= 180.0 && phi > 180.0 {
+            phi = 360.0 - phi;
+        }
+        let (h0, h1, th) = Self::locate(&self.horizontal, phi);
+        let (v0, v1, tv) = Self::locate(&self.vertical, theta);
+        let c = |h: usize| (1.0 - tv) * self.candela[h][v0] + tv * self.candela[h][v1];
+        (1.0 - th) * c(h0) + th * c(h1)
+    }
+}
+
+//発光面の配光
+pub enum EmissionProfile {
+    Uniform,
+    //cos^n で絞った配光
+    Cosine(f64),
+    Ies(IesProfile),
+}
+
+impl EmissionProfile {
+    //cos_thetaは面法線と出射方向のなす角、phiは接線からの方位角(ラジアン)
+    pub fn eval(&self, cos_theta: f64, phi: f64) -> f64 {
+        match self {
+            EmissionProfile::Uniform => 1.0,
+            EmissionProfile::Cosine(n) => cos_theta.max(0.0).powf(*n),
+            EmissionProfile::Ies(ies) => {
+                ies.eval(cos_theta.clamp(-1.0, 1.0).acos().to_degrees(), phi.to_degrees())
+            }
+        }
+    }
+}
+
+//面の接線を基準にした出射方向の方位角
+pub fn emission_azimuth(hit: &HitInfo, wo: Vec3) -> f64 {
+    let n = hit.n;
+    let t = (hit.dpdu - n * n.dot(&hit.dpdu)).norm();
+    let b = n % t;
+    wo.dot(&b).atan2(wo.dot(&t)).rem_euclid(2.0 * PI)
+}
@@ -0,0 +1,166 @@
+use crate::raymod::*;
+
+use std::f64::consts::PI;
+
+//環境光のサンプリング結果 wiは正規化済み
+pub struct EnvSample {
+    pub wi: Vec3,
+    pub li: Color,
+    pub pdf: f64,
+}
+
+//どこにも当たらなかったレイが受け取る放射輝度
+pub trait EnvLight: Sync + Send {
+    fn radiance(&self, d: Vec3) -> Color;
+    //直接光サンプリング用 重点サンプリングできないものはNone
+    fn sample(&self) -> Option<EnvSample> {
+        None
+    }
+    fn pdf(&self, _d: Vec3) -> f64 {
+        0.0
+    }
+}
+
+//一定色の背景
+pub struct ConstantEnv {
+    pub color: Color,
+}
+impl ConstantEnv {
+    pub fn new(color: Color) -> Self {
+        Self { color }
+    }
+}
+impl EnvLight for ConstantEnv {
+    fn radiance(&self, _d: Vec3) -> Color {
+        self.color
+    }
+}
+
+//オリジナル(一週間本)の白から青へのグラデーション
+pub struct GradientSky {
+    pub horizon: Color,
+    pub zenith: Color,
+}
+impl GradientSky {
+    pub fn new(horizon: Color, zenith: Color) -> Self {
+        Self { horizon, zenith }
+    }
+}
+impl Default for GradientSky {
+    fn default() -> Self {
+        Self::new(Color::new(1.0, 1.0, 1.0), Color::new(0.5, 0.7, 1.0))
+    }
+}
+impl EnvLight for GradientSky {
+    fn radiance(&self, d: Vec3) -> Color {
+        let t = 0.5 * (d.norm().y + 1.0);
+        (1.0 - t) * self.horizon + t * self.zenith
+    }
+}
+
+//正距円筒図法の環境マップ 輝度×sinθで重点サンプリングする
+pub struct EnvMap {
+    pixels: Vec<Color>,
+    width: usize,
+    height: usize,
+    rotation: Quat,
+    intensity: f64,
+    distribution: Distribution2D,
+}
+
+impl EnvMap {
+    //rotationはy軸周りの回転(度)
+    pub fn new(texture: &ImageTexture, rotation: f64, intensity: f64) -> Self {
+        let (width, height) = (texture.width(), texture.height());
+        let pixels = texture.pixels().to_vec();
+        let mut func = vec![0.0; width * height];
+        for y in 0..height {
+            let sin_theta = (PI * (y as f64 + 0.5) / height as f64).sin();
+            for x in 0..width {
+                func[x + width * y] = pixels[x + width * y].luminance().max(0.0) * sin_theta;
+            }
+        }
+        Self {
+            pixels,
+            width,
+            height,
+            rotation: Quat::from_rot_y(rotation.to_radians()),
+            intensity,
+            distribution: Distribution2D::new(&func, width, height),
+        }
+    }
+
+    pub fn open(path: &str, rotation: f64, intensity: f64) -> Result<Self, image::ImageError> {
+        Ok(Self::new(&ImageTexture::open(path)?, rotation, intensity))
+    }
+
+    //ワールド方向を地図上の(u,v)にする vは上が0
+    fn direction_to_uv(&self, d: Vec3) -> (f64, f64) {
+        let d = self.rotation.conj().rotate(d.norm());
+        let phi = d.z.atan2(d.x);
+        let theta = d.y.clamp(-1.0, 1.0).acos();
+        ((phi + PI) / (2.0 * PI), theta / PI)
+    }
+
+    fn lookup(&self, u: f64, v: f64) -> Color {
+        let x = ((u * self.width as f64) as usize).min(self.width - 1);
+        let y = ((v * self.height as f64) as usize).min(self.height - 1);
+        self.pixels[x + self.width * y] * self.intensity
+    }
+}
+
+impl EnvLight for EnvMap {
+    fn radiance(&self, d: Vec3) -> Color {
+        let (u, v) = self.direction_to_uv(d);
+        self.lookup(u, v)
+    }
+
+    fn sample(&self) -> Option<EnvSample> {
+        let (u1, u2) = random_2d();
+        let ((u, v), pdf_uv) = self.distribution.sample(u1, u2);
+        if pdf_uv == 0.0 {
+            return None;
+        }
+        let theta = v * PI;
+        let phi = u * 2.0 * PI - PI;
+        let sin_theta = theta.sin();
+        if sin_theta == 0.0 {
+            return None;
+        }
+        let local = Vec3::new(sin_theta * phi.cos(), theta.cos(), sin_theta * phi.sin());
+        Some(EnvSample {
+            wi: self.rotation.rotate(local),
+            li: self.lookup(u, v),
+            pdf: pdf_uv / (2.0 * PI * PI * sin_theta),
+        })
+    }
+
+    fn pdf(&self, d: Vec3) -> f64 {
+        let (u, v) = self.direction_to_uv(d);
+        let sin_theta = (v * PI).sin();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+//MISのパワーヒューリスティック
+pub fn power_heuristic(pa: f64, pb: f64) -> f64 {
+    let (a, b) = (pa * pa, pb * pb);
+    if a + b == 0.0 { 0.0 } else { a / (a + b) }
+}
+
+//環境光を直接サンプリングする(NEE)
+pub fn sample_env(r: &Ray, hit: &HitInfo, world: &dyn Shape, env: &dyn EnvLight) -> Vec3 {
+    if let Some(ls) = env.sample() {
+        let f = hit.m.eval(r, hit, ls.wi);
+        if f.is_black() || world.hit(&Ray::new(hit.p, ls.wi), EPS, f64::MAX).is_some() {
+            return Vec3::zero();
+        }
+        let w = power_heuristic(ls.pdf, hit.m.pdf(r, hit, ls.wi));
+        f.mult(ls.li) * (w / ls.pdf)
+    } else {
+        Vec3::zero()
+    }
+}
@@ -0,0 +1,301 @@
+use crate::raymod::*;
+
+use std::f64::consts::PI;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+//浮動小数点の足し算は順序で結果が変わるので、スレッドの順によらないよう固定小数点の整数で溜める
+//小数部は24ビット(約6e-8刻み) 一画素に溜まる値は2^39(約5.5e11)まで表せるので、4096sppで輝度1e8が続いても収まる
+const FRACTION: f64 = (1u64 << 24) as f64;
+
+//固定小数点にして足す それでもあふれるときは巻き戻って負にならないよう端の値で止めてtrueを返す
+fn add_fixed(cell: &AtomicI64, v: f64) -> bool {
+    let f = (v * FRACTION).round();
+    //asは範囲外の値を端に丸める
+    let n = f as i64;
+    let old = cell.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(x.saturating_add(n))).unwrap();
+    n as f64 != f || old.checked_add(n).is_none()
+}
+
+//画素ごとの値を複数スレッドから足し込む画像
+pub struct SplatBuffer {
+    data: Vec<AtomicI64>,
+}
+
+impl SplatBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        let data = (0..width * height * 3).map(|_| AtomicI64::new(0)).collect();
+        Self { data }
+    }
+
+    //画素番号で足し込む あふれて止めたらtrue
+    pub fn add_at(&self, i: usize, c: Color) -> bool {
+        let mut clipped = false;
+        for (k, v) in [c.x, c.y, c.z].into_iter().enumerate() {
+            clipped |= add_fixed(&self.data[i * 3 + k], v);
+        }
+        clipped
+    }
+
+    pub fn get_at(&self, i: usize) -> Color {
+        let i = i * 3;
+        let v = |k: usize| self.data[i + k].load(Ordering::Relaxed) as f64 / FRACTION;
+        Color::new(v(0), v(1), v(2))
+    }
+}
+
+fn load_all(data: &[AtomicI64]) -> Vec<i64> {
+    data.iter().map(|v| v.load(Ordering::Relaxed)).collect()
+}
+
+fn store_all(data: &[AtomicI64], values: &[i64]) {
+    for (d, &v) in data.iter().zip(values) {
+        d.store(v, Ordering::Relaxed);
+    }
+}
+
+//フィルムに溜まった値そのもの チェックポイントに使う
+pub struct FilmState {
+    pub pixels: Vec<i64>,
+    pub splats: Vec<i64>,
+}
+
+//再構成フィルタの形
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterKind {
+    //画素の中を平均する 半径0.5で従来と同じ
+    Box,
+    //三角形
+    Tent,
+    //端で0になるよう持ち上げたガウス関数 σは半径の1/3
+    Gaussian,
+    //Mitchell-Netravali(B=C=1/3)
+    Mitchell,
+    //半径の数だけ山を持つLanczos窓のsinc
+    Lanczos,
+}
+
+impl FromStr for FilterKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(FilterKind::Box),
+            "tent" => Ok(FilterKind::Tent),
+            "gaussian" => Ok(FilterKind::Gaussian),
+            "mitchell" => Ok(FilterKind::Mitchell),
+            "lanczos" => Ok(FilterKind::Lanczos),
+            _ => Err(format!("unknown filter: {} (box|tent|gaussian|mitchell|lanczos)", s)),
+        }
+    }
+}
+
+impl FilterKind {
+    pub fn default_radius(&self) -> f64 {
+        match self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell => 2.0,
+            FilterKind::Lanczos => 3.0,
+        }
+    }
+}
+
+//x方向とy方向の積で表す分離可能なフィルタ 半径は画素単位
+#[derive(Debug, Clone, Copy)]
+pub struct Filter {
+    pub kind: FilterKind,
+    pub radius: f64,
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind) -> Self {
+        Self { kind, radius: kind.default_radius() }
+    }
+    pub fn with_radius(self, radius: f64) -> Self {
+        Self { radius, ..self }
+    }
+
+    fn eval_1d(&self, x: f64) -> f64 {
+        let r = self.radius;
+        let x = x.abs();
+        if x > r {
+            return 0.0;
+        }
+        match self.kind {
+            FilterKind::Box => 1.0,
+            FilterKind::Tent => r - x,
+            FilterKind::Gaussian => {
+                let g = |x: f64| {
+                    let sigma = r / 3.0;
+                    (-x * x / (2.0 * sigma * sigma)).exp()
+                };
+                (g(x) - g(r)).max(0.0)
+            }
+            FilterKind::Mitchell => {
+                const B: f64 = 1.0 / 3.0;
+                const C: f64 = 1.0 / 3.0;
+                //半径を2に合わせる
+                let x = 2.0 * x / r;
+                if x < 1.0 {
+                    ((12.0 - 9.0 * B - 6.0 * C) * x * x * x + (-18.0 + 12.0 * B + 6.0 * C) * x * x + (6.0 - 2.0 * B))
+                        / 6.0
+                } else {
+                    ((-B - 6.0 * C) * x * x * x + (6.0 * B + 30.0 * C) * x * x + (-12.0 * B - 48.0 * C) * x
+                        + (8.0 * B + 24.0 * C))
+                        / 6.0
+                }
+            }
+            FilterKind::Lanczos => {
+                let sinc = |x: f64| if x < EPS { 1.0 } else { (PI * x).sin() / (PI * x) };
+                sinc(x) * sinc(x / r)
+            }
+        }
+    }
+
+    //フィルタの中心からのずれ(画素単位)に対する重み MitchellとLanczosは負にもなる
+    pub fn eval(&self, dx: f64, dy: f64) -> f64 {
+        self.eval_1d(dx) * self.eval_1d(dy)
+    }
+}
+
+//サンプルをフィルタの重みで近くの画素に配って溜めるフィルム
+//光源側からカメラに届いた寄与(splat)は重みで割らずに別に溜めて、最後にサンプル数で割って足す
+pub struct Film {
+    width: usize,
+    height: usize,
+    filter: Filter,
+    //画素ごとに重み付きの色の和と重みの和
+    pixels: Vec<AtomicI64>,
+    splats: SplatBuffer,
+    //NaNや無限大で捨てたサンプルの数
+    nonfinite: AtomicU64,
+    //あふれて端の値で止めた足し込みの数
+    clipped: AtomicU64,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize, filter: Filter) -> Self {
+        let pixels = (0..width * height * 4).map(|_| AtomicI64::new(0)).collect();
+        Self {
+            width,
+            height,
+            filter,
+            pixels,
+            splats: SplatBuffer::new(width, height),
+            nonfinite: AtomicU64::new(0),
+            clipped: AtomicU64::new(0),
+        }
+    }
+
+    //(px,py)を中心に半径内の画素の番号と重みを並べる 画素の中心は整数+0.5
+    fn footprint(&self, px: f64, py: f64) -> impl Iterator<Item = (usize, f64)> + '_ {
+        let r = self.filter.radius;
+        let x0 = (px - 0.5 - r).ceil().max(0.0) as usize;
+        let x1 = ((px - 0.5 + r).floor() as i64).min(self.width as i64 - 1);
+        let y0 = (py - 0.5 - r).ceil().max(0.0) as usize;
+        let y1 = ((py - 0.5 + r).floor() as i64).min(self.height as i64 - 1);
+        (y0 as i64..=y1).flat_map(move |y| {
+            (x0 as i64..=x1).map(move |x| {
+                let w = self.filter.eval(x as f64 + 0.5 - px, y as f64 + 0.5 - py);
+                (y as usize * self.width + x as usize, w)
+            })
+        })
+    }
+
+    //画面上の位置(画素単位)のサンプルを足す
+    pub fn add_sample(&self, px: f64, py: f64, c: Color) {
+        if !self.check_finite(c) {
+            return;
+        }
+        for (i, w) in self.footprint(px, py) {
+            if w == 0.0 {
+                continue;
+            }
+            let cell = &self.pixels[i * 4..i * 4 + 4];
+            let mut clipped = false;
+            for (k, v) in [c.x * w, c.y * w, c.z * w, w].into_iter().enumerate() {
+                clipped |= add_fixed(&cell[k], v);
+            }
+            self.count_clipped(clipped);
+        }
+    }
+
+    //s,tはCamera::get_rayと同じ0..1の座標 重みの合計が1になるよう配ってエネルギーを保つ
+    pub fn add_splat(&self, s: f64, t: f64, c: Color) {
+        if !self.check_finite(c) {
+            return;
+        }
+        let (px, py) = (s * self.width as f64, t * self.height as f64);
+        let total: f64 = self.footprint(px, py).map(|(_, w)| w).sum();
+        if total.abs() < EPS {
+            let x = (px as usize).min(self.width - 1);
+            let y = (py as usize).min(self.height - 1);
+            self.count_clipped(self.splats.add_at(y * self.width + x, c));
+            return;
+        }
+        for (i, w) in self.footprint(px, py) {
+            if w != 0.0 {
+                self.count_clipped(self.splats.add_at(i, c * (w / total)));
+            }
+        }
+    }
+
+    //NaNや無限大は画素を壊すので足さずに数える
+    fn check_finite(&self, c: Color) -> bool {
+        let finite = c.is_finite();
+        if !finite {
+            self.nonfinite.fetch_add(1, Ordering::Relaxed);
+        }
+        finite
+    }
+
+    fn count_clipped(&self, clipped: bool) {
+        if clipped {
+            self.clipped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    //捨てたサンプルやあふれた画素があれば知らせる文
+    pub fn warning(&self) -> Option<String> {
+        let nonfinite = self.nonfinite.load(Ordering::Relaxed);
+        let clipped = self.clipped.load(Ordering::Relaxed);
+        let mut messages = Vec::new();
+        if nonfinite > 0 {
+            messages.push(format!("{} samples with NaN or infinite radiance were discarded", nonfinite));
+        }
+        if clipped > 0 {
+            messages.push(format!("{} pixel updates overflowed the film and were clamped", clipped));
+        }
+        (!messages.is_empty()).then(|| messages.join(", "))
+    }
+
+    //重みで割った画素の値 splatは含まない
+    pub fn get(&self, x: usize, y: usize) -> Color {
+        let i = (y * self.width + x) * 4;
+        let v = |k: usize| self.pixels[i + k].load(Ordering::Relaxed) as f64 / FRACTION;
+        let w = v(3);
+        if w <= 0.0 { Color::zero() } else { Color::new(v(0), v(1), v(2)) / w }
+    }
+
+    pub fn state(&self) -> FilmState {
+        FilmState { pixels: load_all(&self.pixels), splats: load_all(&self.splats.data) }
+    }
+
+    //同じ大きさのフィルムの状態に戻す
+    pub fn restore(&self, state: &FilmState) -> Result<(), String> {
+        if state.pixels.len() != self.pixels.len() || state.splats.len() != self.splats.data.len() {
+            return Err("film size mismatch".to_string());
+        }
+        store_all(&self.pixels, &state.pixels);
+        store_all(&self.splats.data, &state.splats);
+        Ok(())
+    }
+
+    //画像にする splat_scaleはsplatに掛ける値(画素あたりのサンプル数の逆数)
+    pub fn resolve(&self, splat_scale: f64) -> Vec<Color> {
+        (0..self.width * self.height)
+            .map(|i| self.get(i % self.width, i / self.width) + self.splats.get_at(i) * splat_scale)
+            .collect()
+    }
+}
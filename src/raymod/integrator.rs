@@ -0,0 +1,299 @@
+use crate::raymod::*;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+//カメラレイ一本から放射輝度を求める描画方法
+pub trait Integrator: Sync {
+    fn li(&self, ray: &Ray) -> Color;
+    //光の内訳をAOVに振り分ける版 振り分けない積分器は本画像の値だけを返す
+    fn li_aov(&self, ray: &Ray, _aov: &mut AovSample) -> Color {
+        self.li(ray)
+    }
+    //li_aovでdirect/indirect/emissionを埋めるか
+    fn splits_light(&self) -> bool {
+        false
+    }
+}
+
+//次イベント推定とロシアンルーレット付きのパストレーシング
+pub struct PathTracer<'a> {
+    world: &'a dyn Shape,
+    env: &'a dyn EnvLight,
+    lights: &'a LightSampler,
+    //交差判定の回数の上限 MISの残りを拾うだけの最後の一回は数えない
+    max_depth: i64,
+    //この回数目の反射からロシアンルーレットで打ち切る
+    rr_depth: i64,
+}
+
+impl<'a> PathTracer<'a> {
+    pub fn new(world: &'a dyn Shape, env: &'a dyn EnvLight, lights: &'a LightSampler) -> Self {
+        Self { world, env, lights, max_depth: 32, rr_depth: 3 }
+    }
+    pub fn with_max_depth(self, max_depth: i64) -> Self {
+        Self { max_depth, ..self }
+    }
+    pub fn with_rr_depth(self, rr_depth: i64) -> Self {
+        Self { rr_depth, ..self }
+    }
+}
+
+impl Integrator for PathTracer<'_> {
+    fn li(&self, r: &Ray) -> Color {
+        self.li_aov(r, &mut AovSample::zero())
+    }
+
+    fn splits_light(&self) -> bool {
+        true
+    }
+
+    //経路の寄与をスループットを掛けながら反復で追う
+    fn li_aov(&self, r: &Ray, aov: &mut AovSample) -> Color {
+        let mut l = Vec3::zero();
+        let mut beta = Vec3::new(1.0, 1.0, 1.0);
+        let mut ray = r.clone();
+        //直前の散乱方向の確率密度 カメラレイや鏡面反射は0
+        let mut scatter_pdf = 0.0;
+        //直前に散乱した点と法線 面光源のMIS用
+        let mut prev = (r.o, Vec3::zero());
+        //上限の次の交差では、最後の次イベント推定とMISで重みを分け合った光源と環境光だけを足す
+        for depth in 0..=self.max_depth {
+            let last = depth == self.max_depth;
+            if last && scatter_pdf == 0.0 {
+                break;
+            }
+            start_bounce(depth as usize);
+            let Some(mut hit) = self.world.hit(&ray, EPS, f64::MAX) else {
+                let le = self.env.radiance(ray.d);
+                let w = if scatter_pdf > 0.0 { power_heuristic(scatter_pdf, self.env.pdf(ray.d)) } else { 1.0 };
+                let c = beta.mult(le) * w;
+                aov.add_light(depth, c);
+                l = l + c;
+                break;
+            };
+            hit.footprint = ray.cone_width(hit.t);
+            let sampled = self.lights.is_sampled(&hit);
+            if last && !sampled {
+                break;
+            }
+            //光源サンプリングで数えた面光源は、拡散反射の先で当たった分と重みを分け合う
+            let w = if scatter_pdf > 0.0 && sampled {
+                power_heuristic(scatter_pdf, self.lights.pdf_li(prev.0, prev.1, &hit))
+            } else {
+                1.0
+            };
+            let c = beta.mult(hit.m.emitted(&ray, &hit)) * w;
+            aov.add_light(depth, c);
+            l = l + c;
+            if last {
+                break;
+            }
+            let Some(scatter) = hit.m.scatter(&ray, &hit) else {
+                break;
+            };
+            if !scatter.is_specular() {
+                let direct = sample_env(&ray, &hit, self.world, self.env)
+                    + sample_lights_mis(&ray, &hit, self.world, self.lights);
+                let c = beta.mult(direct);
+                aov.add_light(depth + 1, c);
+                l = l + c;
+            }
+            beta = beta.mult(scatter.albedo);
+            if beta.is_black() {
+                break;
+            }
+            //暗くなった経路は確率的に打ち切り、生き残った分を重くして偏りをなくす
+            if depth + 1 >= self.rr_depth {
+                let q = beta.max_element().min(0.95);
+                if random() >= q {
+                    break;
+                }
+                beta = beta / q;
+            }
+            ray = scatter.ray.with_cone(hit.footprint, ray.spread);
+            scatter_pdf = scatter.pdf;
+            prev = (hit.p, hit.n);
+        }
+        l
+    }
+}
+
+//直接光だけ 鏡面反射・屈折はたどり、最初の拡散面で光源と環境光を次イベント推定して止める
+pub struct DirectLighting<'a> {
+    world: &'a dyn Shape,
+    env: &'a dyn EnvLight,
+    lights: &'a LightSampler,
+    max_depth: i64,
+}
+
+impl<'a> DirectLighting<'a> {
+    pub fn new(world: &'a dyn Shape, env: &'a dyn EnvLight, lights: &'a LightSampler) -> Self {
+        Self { world, env, lights, max_depth: 32 }
+    }
+    pub fn with_max_depth(self, max_depth: i64) -> Self {
+        Self { max_depth, ..self }
+    }
+}
+
+impl Integrator for DirectLighting<'_> {
+    fn li(&self, r: &Ray) -> Color {
+        let mut l = Vec3::zero();
+        let mut beta = Vec3::new(1.0, 1.0, 1.0);
+        let mut ray = r.clone();
+        for depth in 0..self.max_depth {
+            start_bounce(depth as usize);
+            let Some(mut hit) = self.world.hit(&ray, EPS, f64::MAX) else {
+                l = l + beta.mult(self.env.radiance(ray.d));
+                break;
+            };
+            hit.footprint = ray.cone_width(hit.t);
+            l = l + beta.mult(hit.m.emitted(&ray, &hit));
+            let Some(scatter) = hit.m.scatter(&ray, &hit) else {
+                break;
+            };
+            if !scatter.is_specular() {
+                let direct = sample_env(&ray, &hit, self.world, self.env)
+                    + sample_lights(&ray, &hit, self.world, self.lights);
+                l = l + beta.mult(direct);
+                break;
+            }
+            beta = beta.mult(scatter.albedo);
+            if beta.is_black() {
+                break;
+            }
+            ray = scatter.ray.with_cone(hit.footprint, ray.spread);
+        }
+        l
+    }
+}
+
+//アンビエントオクルージョン 法線側の半球でdistance以内に遮るものがない割合
+pub struct AmbientOcclusion<'a> {
+    world: &'a dyn Shape,
+    distance: f64,
+}
+
+impl<'a> AmbientOcclusion<'a> {
+    pub fn new(world: &'a dyn Shape) -> Self {
+        Self { world, distance: f64::MAX }
+    }
+    pub fn with_distance(self, distance: f64) -> Self {
+        Self { distance, ..self }
+    }
+}
+
+impl Integrator for AmbientOcclusion<'_> {
+    fn li(&self, r: &Ray) -> Color {
+        let Some(hit) = self.world.hit(r, EPS, f64::MAX) else {
+            return Color::new(1.0, 1.0, 1.0);
+        };
+        //裏から見た面でも手前側の半球を調べる
+        let n = if hit.n.dot(&r.d) > 0.0 { -hit.n } else { hit.n };
+        //cosに比例した方向を選ぶので、遮られなかった割合がそのままcos重み付きの値になる
+        let mut d = n + Vec3::random_unit();
+        if d.length() < EPS {
+            d = n;
+        }
+        if self.world.hit(&Ray::new(hit.p, d.norm()), EPS10, self.distance).is_some() {
+            Color::zero()
+        } else {
+            Color::new(1.0, 1.0, 1.0)
+        }
+    }
+}
+
+//デバッグ表示の種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DebugMode {
+    //幾何法線 (n+1)/2
+    Normal,
+    //テクスチャ座標 (u,v,0)
+    Uv,
+    //交点までの距離 scaleで1になる
+    Depth,
+    //マテリアルごとの色
+    MaterialId,
+    //BVHの節点を調べた数 scale個で赤になる
+    BvhCost,
+}
+
+pub struct DebugView<'a> {
+    world: &'a dyn Shape,
+    mode: DebugMode,
+    scale: f64,
+    //マテリアルのアドレスからシーンに出てくる順の番号 アドレスは実行ごとに変わるので色には使わない
+    material_ids: HashMap<usize, usize>,
+}
+
+fn material_key(m: &Arc<dyn Material>) -> usize {
+    Arc::as_ptr(m) as *const () as usize
+}
+
+impl<'a> DebugView<'a> {
+    pub fn new(world: &'a dyn Shape, mode: DebugMode) -> Self {
+        let scale = if mode == DebugMode::BvhCost { 64.0 } else { 1.0 };
+        let mut materials = Vec::new();
+        if mode == DebugMode::MaterialId {
+            world.collect_materials(&mut materials);
+        }
+        let mut material_ids = HashMap::new();
+        for m in &materials {
+            let id = material_ids.len();
+            material_ids.entry(material_key(m)).or_insert(id);
+        }
+        Self { world, mode, scale, material_ids }
+    }
+    pub fn with_scale(self, scale: f64) -> Self {
+        Self { scale, ..self }
+    }
+}
+
+//整数から見分けやすい色を作る
+pub fn hash_color(x: u64) -> Color {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    let c = |s: u32| 0.2 + 0.8 * ((z >> s) & 0xff) as f64 / 255.0;
+    Color::new(c(0), c(8), c(16))
+}
+
+//0..1を青→緑→赤に
+pub fn heat_color(t: f64) -> Color {
+    let t = t.clamp(0.0, 1.0);
+    if t < 0.5 {
+        let s = t * 2.0;
+        Color::new(0.0, s, 1.0 - s)
+    } else {
+        let s = (t - 0.5) * 2.0;
+        Color::new(s, 1.0 - s, 0.0)
+    }
+}
+
+impl Integrator for DebugView<'_> {
+    fn li(&self, r: &Ray) -> Color {
+        if self.mode == DebugMode::BvhCost {
+            take_traversal_count();
+            self.world.hit(r, EPS, f64::MAX);
+            return heat_color(take_traversal_count() as f64 / self.scale);
+        }
+        let Some(hit) = self.world.hit(r, EPS, f64::MAX) else {
+            return Color::zero();
+        };
+        match self.mode {
+            //FlipFaceを忘れた壁は法線がカメラと同じ向きになるので色で分かる
+            DebugMode::Normal => (hit.n + Vec3::new(1.0, 1.0, 1.0)) * 0.5,
+            DebugMode::Uv => Color::new(hit.u, hit.v, 0.0),
+            DebugMode::Depth => {
+                let d = hit.t * r.d.length().sqrt() / self.scale;
+                Color::new(d, d, d)
+            }
+            DebugMode::MaterialId => match self.material_ids.get(&material_key(&hit.m)) {
+                Some(&id) => hash_color(id as u64),
+                None => Color::zero(),
+            },
+            DebugMode::BvhCost => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,494 @@
+use crate::raymod::*;
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+//光源から点pへの寄与 wiはpから光源への単位ベクトル
+pub struct LightSample {
+    pub wi: Vec3,
+    pub dist: f64,
+    pub li: Color,
+    pub pdf: f64,
+}
+
+//光源から光を放つ向きのサンプル 双方向パストレーシングの光源側の経路に使う
+//nは面光源の法線(点光源はNone)、hitは面光源上の交点情報
+pub struct LeSample {
+    pub ray: Ray,
+    pub n: Option<Vec3>,
+    pub hit: Option<HitInfo>,
+    pub le: Color,
+    pub pdf_pos: f64,
+    pub pdf_dir: f64,
+}
+
+//シャドウレイで直接サンプリングする光源
+//強度の単位は放射強度[W/sr](平行光源は放射照度[W/m^2])、距離はシーンの単位
+//pdfは立体角あたり 点光源など方向が決まっているものは1
+pub trait Light: Sync + Send {
+    fn sample_li(&self, p: Vec3) -> Option<LightSample>;
+    //点pからsample_liで光源上の点hitが選ばれる立体角あたりの確率密度 レイが当たらない光源は0
+    fn pdf_li(&self, _p: Vec3, _hit: &HitInfo) -> f64 {
+        0.0
+    }
+    //全放射束の目安(輝度) 光源選択の重みに使う
+    fn power(&self) -> f64;
+    //光源BVH用の範囲 平行光源など無限遠にあるものはNone
+    fn bounds(&self) -> Option<LightBounds> {
+        None
+    }
+    //面光源ならその材質 BSDFで当たった発光を二重に数えないために使う
+    fn emitter(&self) -> Option<&Arc<dyn Material>> {
+        None
+    }
+    //光源上の点と放射方向を選ぶ 無限遠の光源は作れないのでNone
+    fn sample_le(&self) -> Option<LeSample> {
+        None
+    }
+    //sample_leで法線nの点から方向wに放つ確率密度(面積あたり,立体角あたり)
+    fn pdf_le(&self, _n: Vec3, _w: Vec3) -> (f64, f64) {
+        (0.0, 0.0)
+    }
+    //位置が一点に決まる光源
+    fn is_delta(&self) -> bool {
+        false
+    }
+}
+
+//球面上の一様な方向
+fn uniform_sphere() -> Vec3 {
+    Vec3::random_unit()
+}
+
+//軸の周りでcos_maxまでの円錐内の一様な方向
+fn uniform_cone(axis: Vec3, cos_max: f64) -> Vec3 {
+    let (u, v) = random_2d();
+    let cos_theta = 1.0 - u * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    let (t, b) = axis.orthonormal_basis();
+    t * (sin_theta * phi.cos()) + b * (sin_theta * phi.sin()) + axis * cos_theta
+}
+
+//点光源
+pub struct PointLight {
+    pub position: Vec3,
+    pub intensity: Color,
+}
+
+impl PointLight {
+    pub fn new(position: Vec3, intensity: Color) -> Self {
+        Self { position, intensity }
+    }
+    //全放射束[W]から作る
+    pub fn from_power(position: Vec3, power: Color) -> Self {
+        Self::new(position, power / (4.0 * PI))
+    }
+}
+
+impl Light for PointLight {
+    fn sample_li(&self, p: Vec3) -> Option<LightSample> {
+        let d = self.position - p;
+        let dist2 = d.length();
+        if dist2 == 0.0 {
+            return None;
+        }
+        let dist = dist2.sqrt();
+        Some(LightSample {
+            wi: d / dist,
+            dist,
+            li: self.intensity / dist2,
+            pdf: 1.0,
+        })
+    }
+    fn power(&self) -> f64 {
+        4.0 * PI * self.intensity.luminance()
+    }
+    fn bounds(&self) -> Option<LightBounds> {
+        Some(LightBounds::new(AABB::new(self.position, self.position), Vec3::yaxis(), self.power(), PI, PI / 2.0))
+    }
+    fn sample_le(&self) -> Option<LeSample> {
+        Some(LeSample {
+            ray: Ray::new(self.position, uniform_sphere()),
+            n: None,
+            hit: None,
+            le: self.intensity,
+            pdf_pos: 1.0,
+            pdf_dir: 1.0 / (4.0 * PI),
+        })
+    }
+    fn pdf_le(&self, _n: Vec3, _w: Vec3) -> (f64, f64) {
+        (0.0, 1.0 / (4.0 * PI))
+    }
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
+
+//スポットライト cos_inner より内側は一定、cos_outer まで滑らかに減衰する
+pub struct SpotLight {
+    pub position: Vec3,
+    pub direction: Vec3,
+    pub intensity: Color,
+    cos_inner: f64,
+    cos_outer: f64,
+}
+
+impl SpotLight {
+    //角度は光軸からの半角(度)
+    pub fn new(position: Vec3, target: Vec3, intensity: Color, inner: f64, outer: f64) -> Self {
+        let outer = outer.max(inner);
+        Self {
+            position,
+            direction: (target - position).norm(),
+            intensity,
+            cos_inner: inner.to_radians().cos(),
+            cos_outer: outer.to_radians().cos(),
+        }
+    }
+    pub fn from_power(position: Vec3, target: Vec3, power: Color, inner: f64, outer: f64) -> Self {
+        let spot = Self::new(position, target, Color::zero(), inner, outer);
+        let solid_angle = 2.0 * PI * (1.0 - 0.5 * (spot.cos_inner + spot.cos_outer));
+        Self { intensity: power / solid_angle, ..spot }
+    }
+    fn falloff(&self, cos_theta: f64) -> f64 {
+        if cos_theta >= self.cos_inner {
+            1.0
+        } else if cos_theta <= self.cos_outer {
+            0.0
+        } else {
+            let t = (cos_theta - self.cos_outer) / (self.cos_inner - self.cos_outer);
+            t * t * (3.0 - 2.0 * t)
+        }
+    }
+}
+
+impl Light for SpotLight {
+    fn sample_li(&self, p: Vec3) -> Option<LightSample> {
+        let d = self.position - p;
+        let dist2 = d.length();
+        if dist2 == 0.0 {
+            return None;
+        }
+        let dist = dist2.sqrt();
+        let wi = d / dist;
+        let falloff = self.falloff((-wi).dot(&self.direction));
+        if falloff == 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            wi,
+            dist,
+            li: self.intensity * (falloff / dist2),
+            pdf: 1.0,
+        })
+    }
+    fn power(&self) -> f64 {
+        let solid_angle = 2.0 * PI * (1.0 - 0.5 * (self.cos_inner + self.cos_outer));
+        self.intensity.luminance() * solid_angle
+    }
+    fn bounds(&self) -> Option<LightBounds> {
+        //減衰が始まるまでを向きの広がり、減衰しきるまでを放射の広がりとする
+        let theta_o = self.cos_inner.clamp(-1.0, 1.0).acos();
+        let theta_e = self.cos_outer.clamp(-1.0, 1.0).acos() - theta_o;
+        let bbox = AABB::new(self.position, self.position);
+        Some(LightBounds::new(bbox, self.direction, self.power(), theta_o, theta_e))
+    }
+    //外側の円錐内で一様に選ぶ
+    fn sample_le(&self) -> Option<LeSample> {
+        let w = uniform_cone(self.direction, self.cos_outer);
+        Some(LeSample {
+            ray: Ray::new(self.position, w),
+            n: None,
+            hit: None,
+            le: self.intensity * self.falloff(w.dot(&self.direction)),
+            pdf_pos: 1.0,
+            pdf_dir: 1.0 / (2.0 * PI * (1.0 - self.cos_outer)),
+        })
+    }
+    fn pdf_le(&self, _n: Vec3, w: Vec3) -> (f64, f64) {
+        if w.dot(&self.direction) >= self.cos_outer {
+            (0.0, 1.0 / (2.0 * PI * (1.0 - self.cos_outer)))
+        } else {
+            (0.0, 0.0)
+        }
+    }
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
+
+//平行光源 directionは光の進む向き
+pub struct DirectionalLight {
+    pub direction: Vec3,
+    pub irradiance: Color,
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vec3, irradiance: Color) -> Self {
+        Self {
+            direction: direction.norm(),
+            irradiance,
+        }
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample_li(&self, _p: Vec3) -> Option<LightSample> {
+        Some(LightSample {
+            wi: -self.direction,
+            dist: f64::INFINITY,
+            li: self.irradiance,
+            pdf: 1.0,
+        })
+    }
+    fn power(&self) -> f64 {
+        self.irradiance.luminance()
+    }
+}
+
+//面光源上の点 座標は形状のローカル座標
+struct SurfacePoint {
+    p: Vec3,
+    n: Vec3,
+    u: f64,
+    v: f64,
+    dpdu: Vec3,
+    dpdv: Vec3,
+}
+
+//面光源の形状
+pub enum AreaShape {
+    Sphere(Sphere),
+    //origin + s*e1 + t*e2 (s,t∈[0,1])の平行四辺形 uvは(s,t)
+    Quad { origin: Vec3, e1: Vec3, e2: Vec3, n: Vec3 },
+    Triangle(Triangle),
+}
+
+impl AreaShape {
+    fn area(&self) -> f64 {
+        match self {
+            AreaShape::Sphere(s) => 4.0 * PI * s.radius * s.radius,
+            AreaShape::Quad { e1, e2, .. } => (*e1 % *e2).length().sqrt(),
+            AreaShape::Triangle(t) => 0.5 * ((t.p[1] - t.p[0]) % (t.p[2] - t.p[0])).length().sqrt(),
+        }
+    }
+
+    //面積について一様に点を選ぶ
+    fn sample_area(&self, u1: f64, u2: f64) -> SurfacePoint {
+        match self {
+            AreaShape::Sphere(s) => {
+                let z = 1.0 - 2.0 * u1;
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                let phi = 2.0 * PI * u2;
+                Self::sphere_point(s, Vec3::new(r * phi.cos(), r * phi.sin(), z))
+            }
+            AreaShape::Quad { origin, e1, e2, n } => SurfacePoint {
+                p: *origin + *e1 * u1 + *e2 * u2,
+                n: *n,
+                u: u1,
+                v: u2,
+                dpdu: *e1,
+                dpdv: *e2,
+            },
+            AreaShape::Triangle(t) => {
+                let su = u1.sqrt();
+                let (b1, b2) = (u2 * su, 1.0 - su);
+                let b0 = 1.0 - b1 - b2;
+                let n = t.normal();
+                let (dpdu, dpdv) = t.tangent(n);
+                SurfacePoint {
+                    p: t.p[0] * b0 + t.p[1] * b1 + t.p[2] * b2,
+                    n,
+                    u: b0 * t.uv[0].0 + b1 * t.uv[1].0 + b2 * t.uv[2].0,
+                    v: b0 * t.uv[0].1 + b1 * t.uv[1].1 + b2 * t.uv[2].1,
+                    dpdu,
+                    dpdv,
+                }
+            }
+        }
+    }
+
+    fn sphere_point(s: &Sphere, n: Vec3) -> SurfacePoint {
+        let (u, v) = Sphere::uv(n);
+        let (dpdu, dpdv) = s.tangent(n);
+        SurfacePoint { p: s.center + n * s.radius, n, u, v, dpdu, dpdv }
+    }
+
+    //点pから見える点を選び、立体角あたりの確率密度と一緒に返す
+    fn sample(&self, p: Vec3) -> Option<(SurfacePoint, f64)> {
+        if let AreaShape::Sphere(s) = self {
+            let dc = s.center - p;
+            let d2 = dc.length();
+            let r2 = s.radius * s.radius;
+            if d2 > r2 {
+                //球の外からは見える円錐の中を一様に選ぶ
+                let sin2_max = r2 / d2;
+                let cos_max = (1.0 - sin2_max).max(0.0).sqrt();
+                let one_minus_cos_max = sin2_max / (1.0 + cos_max);
+                let (u, v) = random_2d();
+                let cos_theta = 1.0 - u * one_minus_cos_max;
+                let sin2_theta = (1.0 - cos_theta * cos_theta).max(0.0);
+                let phi = 2.0 * PI * v;
+                let d = d2.sqrt();
+                let axis = dc / d;
+                let (t, b) = axis.orthonormal_basis();
+                let sin_theta = sin2_theta.sqrt();
+                let wi = t * (sin_theta * phi.cos()) + b * (sin_theta * phi.sin()) + axis * cos_theta;
+                let dist = d * cos_theta - (r2 - d2 * sin2_theta).max(0.0).sqrt();
+                let n = (p + wi * dist - s.center).norm();
+                return Some((Self::sphere_point(s, n), 1.0 / (2.0 * PI * one_minus_cos_max)));
+            }
+        }
+        let (u, v) = random_2d();
+        let sp = self.sample_area(u, v);
+        let d = sp.p - p;
+        let dist2 = d.length();
+        let cos_light = (d.dot(&sp.n) / dist2.sqrt()).abs();
+        if dist2 == 0.0 || cos_light < EPS {
+            return None;
+        }
+        let pdf = dist2 / (cos_light * self.area());
+        Some((sp, pdf))
+    }
+
+    //点pからsampleで面上の点qが選ばれる立体角あたりの確率密度
+    fn pdf(&self, p: Vec3, q: Vec3) -> f64 {
+        let n = match self {
+            AreaShape::Sphere(s) => {
+                let d2 = (s.center - p).length();
+                let r2 = s.radius * s.radius;
+                if d2 > r2 {
+                    let sin2_max = r2 / d2;
+                    let cos_max = (1.0 - sin2_max).max(0.0).sqrt();
+                    return 1.0 / (2.0 * PI * sin2_max / (1.0 + cos_max));
+                }
+                (q - s.center) / s.radius
+            }
+            AreaShape::Quad { n, .. } => *n,
+            AreaShape::Triangle(t) => t.normal(),
+        };
+        let d = q - p;
+        let dist2 = d.length();
+        if dist2 == 0.0 {
+            return 0.0;
+        }
+        let cos_light = (d.dot(&n) / dist2.sqrt()).abs();
+        if cos_light < EPS {
+            return 0.0;
+        }
+        dist2 / (cos_light * self.area())
+    }
+}
+
+//DiffuseLightを持つ形状から作る面光源
+pub struct AreaLight {
+    shape: AreaShape,
+    material: Arc<dyn Material>,
+    xf: Transform,
+    power: f64,
+    two_sided: bool,
+}
+
+impl AreaLight {
+    pub fn new(shape: AreaShape, material: Arc<dyn Material>, xf: Transform) -> Self {
+        let mut light = Self { shape, material, xf, power: 0.0, two_sided: false };
+        //面上の数点で法線方向の放射輝度を測って放射束を見積もる
+        const N: usize = 4;
+        let (mut front, mut back) = (0.0, 0.0);
+        for i in 0..N {
+            for j in 0..N {
+                let u1 = (i as f64 + 0.5) / N as f64;
+                let u2 = (j as f64 + 0.5) / N as f64;
+                let hit = light.surface_hit(light.shape.sample_area(u1, u2));
+                front += light.material.emitted(&Ray::new(hit.p + hit.n, -hit.n), &hit).luminance();
+                back += light.material.emitted(&Ray::new(hit.p - hit.n, hit.n), &hit).luminance();
+            }
+        }
+        let area = light.shape.area();
+        let scale = PI * area / (N * N) as f64;
+        light.two_sided = back > 0.0;
+        light.power = (front + back) * scale;
+        light
+    }
+
+    //ローカルの点をワールドの交点情報にする
+    fn surface_hit(&self, sp: SurfacePoint) -> HitInfo {
+        let xf = &self.xf;
+        HitInfo::new(0.0, xf.point(sp.p), xf.normal(sp.n), Arc::clone(&self.material), sp.u, sp.v)
+            .with_tangent(xf.vector(sp.dpdu), xf.vector(sp.dpdv))
+    }
+}
+
+impl Light for AreaLight {
+    fn sample_li(&self, p: Vec3) -> Option<LightSample> {
+        let (sp, pdf) = self.shape.sample(self.xf.inverse_point(p))?;
+        let mut hit = self.surface_hit(sp);
+        let d = hit.p - p;
+        let dist = d.length().sqrt();
+        if pdf <= 0.0 || dist == 0.0 {
+            return None;
+        }
+        let wi = d / dist;
+        hit.t = dist;
+        let li = self.material.emitted(&Ray::new(p, wi), &hit);
+        if li.is_black() {
+            return None;
+        }
+        Some(LightSample { wi, dist, li, pdf })
+    }
+    fn pdf_li(&self, p: Vec3, hit: &HitInfo) -> f64 {
+        self.shape.pdf(self.xf.inverse_point(p), self.xf.inverse_point(hit.p))
+    }
+    fn power(&self) -> f64 {
+        self.power
+    }
+    fn bounds(&self) -> Option<LightBounds> {
+        let xf = &self.xf;
+        match &self.shape {
+            AreaShape::Sphere(s) => {
+                let c = xf.point(s.center);
+                let r = Vec3::new(s.radius, s.radius, s.radius);
+                Some(LightBounds::new(AABB::new(c - r, c + r), Vec3::yaxis(), self.power, PI, PI / 2.0))
+            }
+            AreaShape::Quad { origin, e1, e2, n } => {
+                let corners = [*origin, *origin + *e1, *origin + *e2, *origin + *e1 + *e2];
+                let bbox = LightBounds::enclose(corners.iter().map(|c| xf.point(*c)));
+                Some(LightBounds::new(bbox, xf.normal(*n), self.power, 0.0, PI / 2.0).with_two_sided(self.two_sided))
+            }
+            AreaShape::Triangle(t) => {
+                let bbox = LightBounds::enclose(t.p.iter().map(|c| xf.point(*c)));
+                Some(LightBounds::new(bbox, xf.normal(t.normal()), self.power, 0.0, PI / 2.0).with_two_sided(self.two_sided))
+            }
+        }
+    }
+    fn emitter(&self) -> Option<&Arc<dyn Material>> {
+        Some(&self.material)
+    }
+    //面上は一様、方向は法線の周りでcosに比例(両面なら表裏を半々)
+    fn sample_le(&self) -> Option<LeSample> {
+        let (u, v) = random_2d();
+        let hit = self.surface_hit(self.shape.sample_area(u, v));
+        let mut n = hit.n;
+        if self.two_sided && random() < 0.5 {
+            n = -n;
+        }
+        let mut w = n + uniform_sphere();
+        if w.length() < EPS {
+            w = n;
+        }
+        let w = w.norm();
+        let (pdf_pos, pdf_dir) = self.pdf_le(hit.n, w);
+        let le = self.material.emitted(&Ray::new(hit.p + w, -w), &hit);
+        Some(LeSample { ray: Ray::new(hit.p, w), n: Some(hit.n), hit: Some(hit), le, pdf_pos, pdf_dir })
+    }
+    fn pdf_le(&self, n: Vec3, w: Vec3) -> (f64, f64) {
+        let cos = w.dot(&n);
+        let pdf_dir = if self.two_sided {
+            0.5 * cos.abs() / PI
+        } else {
+            cos.max(0.0) / PI
+        };
+        (1.0 / self.shape.area(), pdf_dir)
+    }
+}
@@ -0,0 +1,396 @@
+use crate::raymod::*;
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+//光源の範囲 位置の箱と、法線の向きの円錐(軸w,半角theta_o)と放射の広がりtheta_e
+//Conty & Kulla, Importance Sampling of Many Lights with Adaptive Tree Splitting (2018)
+#[derive(Clone, Copy)]
+pub struct LightBounds {
+    pub bbox: AABB,
+    pub w: Vec3,
+    pub phi: f64,
+    pub theta_o: f64,
+    pub theta_e: f64,
+    pub two_sided: bool,
+}
+
+impl LightBounds {
+    pub fn new(bbox: AABB, w: Vec3, phi: f64, theta_o: f64, theta_e: f64) -> Self {
+        Self { bbox, w: w.norm(), phi, theta_o, theta_e, two_sided: false }
+    }
+    pub fn with_two_sided(self, two_sided: bool) -> Self {
+        Self { two_sided, ..self }
+    }
+
+    //点を全部含む箱
+    pub fn enclose(points: impl Iterator<Item = Vec3>) -> AABB {
+        let mut min = Vec3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+        let mut max = Vec3::new(-f64::INFINITY, -f64::INFINITY, -f64::INFINITY);
+        for p in points {
+            for a in 0..3 {
+                min[a] = min[a].min(p[a]);
+                max[a] = max[a].max(p[a]);
+            }
+        }
+        AABB::new(min, max)
+    }
+
+    fn centroid(&self) -> Vec3 {
+        (self.bbox.min + self.bbox.max) * 0.5
+    }
+
+    //二つの向きの円錐を含む円錐
+    fn cone_union(wa: Vec3, theta_a: f64, wb: Vec3, theta_b: f64) -> (Vec3, f64) {
+        let theta_d = wa.dot(&wb).clamp(-1.0, 1.0).acos();
+        if (theta_d + theta_b).min(PI) <= theta_a {
+            return (wa, theta_a);
+        }
+        if (theta_d + theta_a).min(PI) <= theta_b {
+            return (wb, theta_b);
+        }
+        let theta_o = 0.5 * (theta_a + theta_d + theta_b);
+        if theta_o >= PI {
+            return (wa, PI);
+        }
+        let axis = wa % wb;
+        if axis.length() < EPS * EPS {
+            return (wa, PI);
+        }
+        let w = Quat::from_rot(axis.norm(), theta_o - theta_a).rotate(wa);
+        (w, theta_o)
+    }
+
+    pub fn union(&self, other: &LightBounds) -> LightBounds {
+        if self.phi <= 0.0 {
+            return *other;
+        }
+        if other.phi <= 0.0 {
+            return *self;
+        }
+        let (w, theta_o) = Self::cone_union(self.w, self.theta_o, other.w, other.theta_o);
+        LightBounds {
+            bbox: surrounding_box(&self.bbox, &other.bbox),
+            w,
+            phi: self.phi + other.phi,
+            theta_o,
+            theta_e: self.theta_e.max(other.theta_e),
+            two_sided: self.two_sided || other.two_sided,
+        }
+    }
+
+    //点p(法線n)での寄与の見積もり 距離の二乗で減衰し、向きの円錐から外れると弱くなる
+    pub fn importance(&self, p: Vec3, n: Vec3) -> f64 {
+        let pc = self.centroid();
+        let r2 = (self.bbox.max - self.bbox.min).length() * 0.25;
+        let dist2 = (p - pc).length();
+        let d2 = dist2.max(r2).max(EPS);
+        let wi = if dist2 > 0.0 { (p - pc) / dist2.sqrt() } else { self.w };
+        let mut cos_w = self.w.dot(&wi);
+        if self.two_sided {
+            cos_w = cos_w.abs();
+        }
+        let theta_w = cos_w.clamp(-1.0, 1.0).acos();
+        //箱が見込む角度
+        let theta_b = if dist2 <= r2 { PI } else { (r2 / dist2).sqrt().asin() };
+        let theta_p = (theta_w - self.theta_o - theta_b).max(0.0);
+        if theta_p >= self.theta_e {
+            return 0.0;
+        }
+        let theta_i = wi.dot(&n).abs().clamp(0.0, 1.0).acos();
+        let cos_i = (theta_i - theta_b).max(0.0).cos();
+        self.phi * theta_p.cos().max(0.0) * cos_i / d2
+    }
+}
+
+enum LightNode {
+    Leaf { bounds: LightBounds, light: usize },
+    Branch { bounds: LightBounds, left: usize, right: usize },
+}
+
+impl LightNode {
+    fn bounds(&self) -> &LightBounds {
+        match self {
+            LightNode::Leaf { bounds, .. } => bounds,
+            LightNode::Branch { bounds, .. } => bounds,
+        }
+    }
+}
+
+//光源の階層 各節点で子の寄与の見積もりに比例して降りていく
+#[allow(clippy::upper_case_acronyms)]
+pub struct LightBVH {
+    nodes: Vec<LightNode>,
+    root: Option<usize>,
+    //選ばれる確率を後から求めるための、節点の親と光源番号の葉
+    parents: Vec<Option<usize>>,
+    leaves: HashMap<usize, usize>,
+}
+
+impl LightBVH {
+    //(光源番号,範囲)の組から作る
+    pub fn new(mut lights: Vec<(usize, LightBounds)>) -> Self {
+        let mut bvh = Self { nodes: Vec::new(), root: None, parents: Vec::new(), leaves: HashMap::new() };
+        if !lights.is_empty() {
+            bvh.root = Some(bvh.build(&mut lights));
+        }
+        bvh.parents = vec![None; bvh.nodes.len()];
+        for (i, node) in bvh.nodes.iter().enumerate() {
+            match node {
+                LightNode::Leaf { light, .. } => {
+                    bvh.leaves.insert(*light, i);
+                }
+                LightNode::Branch { left, right, .. } => {
+                    bvh.parents[*left] = Some(i);
+                    bvh.parents[*right] = Some(i);
+                }
+            }
+        }
+        bvh
+    }
+
+    //重心の広がりが一番大きい軸で半分に分ける
+    fn build(&mut self, lights: &mut [(usize, LightBounds)]) -> usize {
+        if lights.len() == 1 {
+            let (light, bounds) = lights[0];
+            self.nodes.push(LightNode::Leaf { bounds, light });
+            return self.nodes.len() - 1;
+        }
+        let centroids = LightBounds::enclose(lights.iter().map(|(_, b)| b.centroid()));
+        let extent = centroids.max - centroids.min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        lights.sort_unstable_by(|a, b| a.1.centroid()[axis].partial_cmp(&b.1.centroid()[axis]).unwrap());
+        let mid = lights.len() / 2;
+        let (l, r) = lights.split_at_mut(mid);
+        let left = self.build(l);
+        let right = self.build(r);
+        let bounds = self.nodes[left].bounds().union(self.nodes[right].bounds());
+        self.nodes.push(LightNode::Branch { bounds, left, right });
+        self.nodes.len() - 1
+    }
+
+    //光源番号と選んだ確率を返す
+    pub fn sample(&self, p: Vec3, n: Vec3, mut u: f64) -> Option<(usize, f64)> {
+        let mut node = self.root?;
+        let mut pmf = 1.0;
+        loop {
+            match &self.nodes[node] {
+                LightNode::Leaf { bounds, light } => {
+                    return if bounds.importance(p, n) > 0.0 { Some((*light, pmf)) } else { None };
+                }
+                LightNode::Branch { left, right, .. } => {
+                    let il = self.nodes[*left].bounds().importance(p, n);
+                    let ir = self.nodes[*right].bounds().importance(p, n);
+                    if il + ir <= 0.0 {
+                        return None;
+                    }
+                    let pl = il / (il + ir);
+                    if u < pl {
+                        u = (u / pl).min(1.0 - EPS);
+                        pmf *= pl;
+                        node = *left;
+                    } else {
+                        u = ((u - pl) / (1.0 - pl)).min(1.0 - EPS);
+                        pmf *= 1.0 - pl;
+                        node = *right;
+                    }
+                }
+            }
+        }
+    }
+
+    //点p(法線n)でsampleが光源番号lightを選ぶ確率 葉から根へ各節点での選ばれやすさを掛ける
+    pub fn pmf(&self, p: Vec3, n: Vec3, light: usize) -> f64 {
+        let Some(&leaf) = self.leaves.get(&light) else {
+            return 0.0;
+        };
+        if self.nodes[leaf].bounds().importance(p, n) <= 0.0 {
+            return 0.0;
+        }
+        let mut pmf = 1.0;
+        let mut node = leaf;
+        while let Some(parent) = self.parents[node] {
+            let LightNode::Branch { left, right, .. } = &self.nodes[parent] else {
+                unreachable!();
+            };
+            let il = self.nodes[*left].bounds().importance(p, n);
+            let ir = self.nodes[*right].bounds().importance(p, n);
+            if il + ir <= 0.0 {
+                return 0.0;
+            }
+            pmf *= if node == *left { il } else { ir } / (il + ir);
+            node = parent;
+        }
+        pmf
+    }
+}
+
+//光源の選び方
+#[derive(Debug, Clone, Copy)]
+pub enum LightSelection {
+    //光源BVHで交点ごとの寄与に比例して選ぶ
+    Bvh,
+    //放射束に比例して選ぶ(比較用)
+    Power,
+}
+
+impl FromStr for LightSelection {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bvh" => Ok(LightSelection::Bvh),
+            "power" => Ok(LightSelection::Power),
+            _ => Err(format!("unknown light sampler: {} (bvh|power)", s)),
+        }
+    }
+}
+
+//次イベント推定で一つだけ光源を選ぶ 平行光源は範囲がないので別枠で一様に選ぶ
+pub struct LightSampler {
+    lights: Vec<Box<dyn Light>>,
+    infinite: Vec<usize>,
+    finite: Vec<usize>,
+    bvh: LightBVH,
+    power: Option<Distribution1D>,
+    selection: LightSelection,
+    //光源ごとに、次イベント推定で選ばれることがあるならfiniteの中の位置
+    slot: Vec<Option<usize>>,
+    //光源側の経路の始点に選ばれる面積あたりの確率密度 面光源以外は0
+    origin: Vec<f64>,
+}
+
+impl LightSampler {
+    pub fn new(lights: Vec<Box<dyn Light>>, selection: LightSelection) -> Self {
+        let mut infinite = Vec::new();
+        let mut finite = Vec::new();
+        let mut bounded = Vec::new();
+        for (i, light) in lights.iter().enumerate() {
+            match light.bounds() {
+                Some(b) if b.phi > 0.0 => {
+                    finite.push(i);
+                    bounded.push((i, b));
+                }
+                Some(_) => {}
+                None => infinite.push(i),
+            }
+        }
+        let power = if finite.is_empty() {
+            None
+        } else {
+            let func: Vec<f64> = finite.iter().map(|&i| lights[i].power()).collect();
+            Some(Distribution1D::new(&func))
+        };
+        //面光源ごとに、その面上の点が光源側の経路の始点に選ばれる面積あたりの確率密度
+        let total: f64 = finite.iter().map(|&i| lights[i].power()).sum();
+        let mut origin = vec![0.0; lights.len()];
+        let mut slot = vec![None; lights.len()];
+        for (k, &i) in finite.iter().enumerate() {
+            slot[i] = Some(k);
+            if lights[i].emitter().is_some() && total > 0.0 {
+                origin[i] = lights[i].power() / total * lights[i].pdf_le(Vec3::yaxis(), Vec3::yaxis()).0;
+            }
+        }
+        Self {
+            lights,
+            infinite,
+            finite,
+            bvh: LightBVH::new(bounded),
+            power,
+            selection,
+            slot,
+            origin,
+        }
+    }
+
+    //当たった形状の発光が光源サンプリングで数えられているか
+    //同じ材質でもAlphaMaskの中など光源として集められていない形状は数えない
+    pub fn is_sampled(&self, hit: &HitInfo) -> bool {
+        hit.light.is_some_and(|i| self.slot[i].is_some())
+    }
+
+    //BSDFで当たった光源上の点を、点p(法線n)からの次イベント推定で選ぶ立体角あたりの確率密度 MIS用
+    pub fn pdf_li(&self, p: Vec3, n: Vec3, hit: &HitInfo) -> f64 {
+        let Some((i, k)) = hit.light.and_then(|i| Some((i, self.slot[i]?))) else {
+            return 0.0;
+        };
+        let p_inf = self.infinite.len() as f64 / (self.infinite.len() + 1) as f64;
+        let pmf = match self.selection {
+            LightSelection::Bvh => self.bvh.pmf(p, n, i),
+            LightSelection::Power => self.power.as_ref().map_or(0.0, |d| d.pdf_discrete(k)),
+        };
+        pmf * (1.0 - p_inf) * self.lights[i].pdf_li(p, hit)
+    }
+
+    //当たった発光体の点が光源側の経路の始点に選ばれる確率密度(面積あたり)と、その形状の光源
+    pub fn emitter_origin(&self, hit: &HitInfo) -> Option<(&dyn Light, f64)> {
+        let i = hit.light?;
+        (self.origin[i] > 0.0).then(|| (self.lights[i].as_ref(), self.origin[i]))
+    }
+
+    //放射束に比例して範囲のある光源を一つ選ぶ 双方向パストレーシングの光源側で使う
+    pub fn sample_power(&self, u: f64) -> Option<(&dyn Light, f64)> {
+        let (k, pmf) = self.power.as_ref()?.sample_discrete(u);
+        Some((self.lights[self.finite[k]].as_ref(), pmf))
+    }
+
+    //平行光源など無限遠の光源
+    pub fn infinite_lights(&self) -> impl Iterator<Item = &dyn Light> {
+        self.infinite.iter().map(|&i| self.lights[i].as_ref())
+    }
+
+    //点p(法線n)から見て光源を一つ選び、その確率と一緒に返す
+    pub fn sample(&self, p: Vec3, n: Vec3) -> Option<(&dyn Light, f64)> {
+        let n_inf = self.infinite.len();
+        let has_finite = !self.finite.is_empty();
+        let p_inf = n_inf as f64 / (n_inf + has_finite as usize) as f64;
+        let u = random();
+        if u < p_inf {
+            let i = ((u / p_inf * n_inf as f64) as usize).min(n_inf - 1);
+            return Some((self.lights[self.infinite[i]].as_ref(), p_inf / n_inf as f64));
+        }
+        let u = ((u - p_inf) / (1.0 - p_inf)).min(1.0 - EPS);
+        let (i, pmf) = match self.selection {
+            LightSelection::Bvh => self.bvh.sample(p, n, u)?,
+            LightSelection::Power => {
+                let (k, pmf) = self.power.as_ref()?.sample_discrete(u);
+                (self.finite[k], pmf)
+            }
+        };
+        Some((self.lights[i].as_ref(), pmf * (1.0 - p_inf)))
+    }
+}
+
+//点光源や面光源から一つ選んでシャドウレイで直接サンプリングする
+pub fn sample_lights(r: &Ray, hit: &HitInfo, world: &dyn Shape, lights: &LightSampler) -> Vec3 {
+    sample_lights_weighted(r, hit, world, lights, false)
+}
+
+//sample_lightsと同じだが、面光源はBSDFで当たった分とパワーヒューリスティックで重みを分け合う
+pub fn sample_lights_mis(r: &Ray, hit: &HitInfo, world: &dyn Shape, lights: &LightSampler) -> Vec3 {
+    sample_lights_weighted(r, hit, world, lights, true)
+}
+
+fn sample_lights_weighted(r: &Ray, hit: &HitInfo, world: &dyn Shape, lights: &LightSampler, mis: bool) -> Vec3 {
+    if let Some((light, pmf)) = lights.sample(hit.p, hit.n)
+        && let Some(ls) = light.sample_li(hit.p)
+    {
+        let f = hit.m.eval(r, hit, ls.wi);
+        if f.is_black() {
+            return Vec3::zero();
+        }
+        let shadow = Ray::new(hit.p, ls.wi);
+        if world.hit(&shadow, EPS, ls.dist * (1.0 - EPS10)).is_none() {
+            let pdf = ls.pdf * pmf;
+            let w = if mis && !light.is_delta() { power_heuristic(pdf, hit.m.pdf(r, hit, ls.wi)) } else { 1.0 };
+            return f.mult(ls.li) * (w / pdf);
+        }
+    }
+    Vec3::zero()
+}
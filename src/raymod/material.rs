@@ -1,4 +1,6 @@
 use crate::raymod::*;
+use std::f64::consts::PI;
+use std::sync::Arc;
 
 #[allow(unused)]
 pub trait Material: Sync + Send {
@@ -6,10 +8,39 @@ pub trait Material: Sync + Send {
     fn emitted(&self, ray: &Ray, hit: &HitInfo) -> Color {
         Color::zero()
     }
+    //光源サンプリング用 入射方向wi(正規化済み)に対するBSDF×cos
+    //鏡面など方向を評価できない材質はゼロのまま
+    fn eval(&self, ray: &Ray, hit: &HitInfo, wi: Vec3) -> Color {
+        Color::zero()
+    }
+    //scatterがwiを選ぶ確率密度(立体角)
+    fn pdf(&self, ray: &Ray, hit: &HitInfo, wi: Vec3) -> f64 {
+        0.0
+    }
+    //面光源として光源サンプリングの対象にするか
+    fn is_emissive(&self) -> bool {
+        false
+    }
+    //AOVやデノイザ用の反射率 乱数を使わずに決まる値
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        Color::new(1.0, 1.0, 1.0)
+    }
+    //法線マップなどを反映したシェーディング法線
+    fn normal_at(&self, hit: &HitInfo) -> Vec3 {
+        hit.n
+    }
 }
 
 pub trait Texture: Sync + Send {
     fn value(&self, u: f64, v: f64, p: Vec3) -> Color;
+    //不透明度 アルファチャンネルを持たないテクスチャは常に不透明
+    fn alpha(&self, _u: f64, _v: f64, _p: Vec3) -> f64 {
+        1.0
+    }
+    //交点のフットプリントを使ってフィルタリングする版
+    fn value_at(&self, hit: &HitInfo) -> Color {
+        self.value(hit.u, hit.v, hit.p)
+    }
 }
 
 pub struct ColorTexture {
@@ -48,61 +79,285 @@ impl Texture for CheckerTexture {
             self.even.value(u, v, p)
         }
     }
+    fn value_at(&self, hit: &HitInfo) -> Color {
+        let p = hit.p;
+        let sines = (p.x * self.freq).sin() * (p.y * self.freq).sin() * (p.z * self.freq).sin();
+        if sines < 0.0 {
+            self.odd.value_at(hit)
+        } else {
+            self.even.value_at(hit)
+        }
+    }
 }
 
-pub struct ImageTexture {
+//テクスチャ補間方法
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextureFilter {
+    Nearest,
+    Bilinear,
+    Trilinear,
+}
+
+//uvが0..1を外れたときの扱い
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WrapMode {
+    Clamp,
+    Repeat,
+    Mirror,
+}
+
+impl WrapMode {
+    fn wrap(&self, i: i64, n: usize) -> usize {
+        let n = n as i64;
+        match self {
+            WrapMode::Clamp => i.clamp(0, n - 1) as usize,
+            WrapMode::Repeat => i.rem_euclid(n) as usize,
+            WrapMode::Mirror => {
+                let m = i.rem_euclid(2 * n);
+                (if m < n { m } else { 2 * n - 1 - m }) as usize
+            }
+        }
+    }
+}
+
+struct MipLevel {
     pixels: Vec<Vec3>,
     width: usize,
     height: usize,
 }
 
+impl MipLevel {
+    //2x2の平均で半分の解像度を作る
+    fn downsample(&self) -> MipLevel {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut pixels = vec![Vec3::zero(); width * height];
+        for y in 0..height {
+            for x in 0..width {
+                let mut c = Vec3::zero();
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let sx = (2 * x + dx).min(self.width - 1);
+                    let sy = (2 * y + dy).min(self.height - 1);
+                    c = c + self.pixels[sx + self.width * sy];
+                }
+                pixels[x + width * y] = c / 4.0;
+            }
+        }
+        MipLevel { pixels, width, height }
+    }
+}
+
+pub struct ImageTexture {
+    levels: Vec<MipLevel>,
+    alpha: Vec<f64>,
+    width: usize,
+    height: usize,
+    filter: TextureFilter,
+    wrap: WrapMode,
+    uv_scale: (f64, f64),
+    uv_offset: (f64, f64),
+    uv_rotation: f64,
+}
+
 impl ImageTexture {
+    //読み込めなかったときはエラーを表示して1x1のマゼンタで代用する
     pub fn new(path: &str) -> Self {
-        let rgbimg = image::open(path).unwrap().to_rgb8();
-        let (w, h) = rgbimg.dimensions();
+        Self::open(path).unwrap_or_else(|e| {
+            eprintln!("failed to load texture {}: {}", path, e);
+            Self::from_pixels(vec![Color::new(1.0, 0.0, 1.0)], vec![1.0], 1, 1)
+        })
+    }
+
+    //8bit画像に加えてRadiance HDR(.hdr)やOpenEXR(.exr)も浮動小数点のまま読み込む
+    pub fn open(path: &str) -> Result<Self, image::ImageError> {
+        let rgbaimg = image::open(path)?.to_rgba32f();
+        let (w, h) = rgbaimg.dimensions();
         let mut image = vec![Vec3::zero(); (w * h) as usize];
-        for (i, (_, _, pixel)) in image.iter_mut().zip(rgbimg.enumerate_pixels()) {
-            *i = Color::from_rgb(pixel[0], pixel[1], pixel[2]);
+        let mut alpha = vec![1.0; (w * h) as usize];
+        let texels = image.iter_mut().zip(alpha.iter_mut());
+        for ((i, a), (_, _, pixel)) in texels.zip(rgbaimg.enumerate_pixels()) {
+            *i = Color::new(pixel[0] as f64, pixel[1] as f64, pixel[2] as f64);
+            *a = pixel[3] as f64;
         }
+        Ok(Self::from_pixels(image, alpha, w as usize, h as usize))
+    }
+
+    pub fn from_pixels(pixels: Vec<Vec3>, alpha: Vec<f64>, width: usize, height: usize) -> Self {
+        let mut texture = Self {
+            levels: Vec::new(),
+            alpha,
+            width,
+            height,
+            filter: TextureFilter::Nearest,
+            wrap: WrapMode::Clamp,
+            uv_scale: (1.0, 1.0),
+            uv_offset: (0.0, 0.0),
+            uv_rotation: 0.0,
+        };
+        texture.build_mipmap(pixels);
+        texture
+    }
+
+    //8bit値をsRGBとしてリニアに戻す
+    pub fn with_srgb(mut self) -> Self {
+        let image: Vec<Vec3> = self.levels[0]
+            .pixels
+            .iter()
+            .map(|c| Color::new(srgb_to_linear(c.x), srgb_to_linear(c.y), srgb_to_linear(c.z)))
+            .collect();
+        self.build_mipmap(image);
+        self
+    }
+    pub fn with_filter(self, filter: TextureFilter) -> Self {
+        Self { filter, ..self }
+    }
+    pub fn with_wrap(self, wrap: WrapMode) -> Self {
+        Self { wrap, ..self }
+    }
+    //uvを拡大・回転(度)してからずらす
+    pub fn with_uv_transform(self, scale: (f64, f64), offset: (f64, f64), rotation: f64) -> Self {
         Self {
-            pixels: image,
-            width: w as usize,
-            height: h as usize,
+            uv_scale: scale,
+            uv_offset: offset,
+            uv_rotation: rotation.to_radians(),
+            ..self
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    pub fn height(&self) -> usize {
+        self.height
+    }
+    //フィルタ等を通さない元画像
+    pub fn pixels(&self) -> &[Vec3] {
+        &self.levels[0].pixels
+    }
+
+    fn build_mipmap(&mut self, image: Vec<Vec3>) {
+        let mut levels = vec![MipLevel { pixels: image, width: self.width, height: self.height }];
+        loop {
+            let last = levels.last().unwrap();
+            if last.width == 1 && last.height == 1 {
+                break;
+            }
+            let next = last.downsample();
+            levels.push(next);
         }
+        self.levels = levels;
     }
 
+    #[allow(dead_code)]
     pub fn sample(&self, u: i64, v: i64) -> Color {
-        let tu = if u < 0 {
-            0
-        } else if u as usize >= self.width {
-            self.width - 1
-        } else {
-            u as usize
-        };
-        let tv = if v < 0 {
-            0
-        } else if v as usize >= self.height {
-            self.height - 1
-        } else {
-            v as usize
-        };
-        self.pixels[tu + self.width * tv]
+        self.texel(0, u, v)
+    }
+
+    pub fn sample_alpha(&self, u: i64, v: i64) -> f64 {
+        let x = self.wrap.wrap(u, self.width);
+        let y = self.wrap.wrap(v, self.height);
+        self.alpha[x + self.width * y]
+    }
+
+    fn texel(&self, level: usize, u: i64, v: i64) -> Color {
+        let l = &self.levels[level];
+        let x = self.wrap.wrap(u, l.width);
+        let y = self.wrap.wrap(v, l.height);
+        l.pixels[x + l.width * y]
+    }
+
+    fn transform_uv(&self, u: f64, v: f64) -> (f64, f64) {
+        let (su, sv) = (u * self.uv_scale.0, v * self.uv_scale.1);
+        let (s, c) = self.uv_rotation.sin_cos();
+        (su * c - sv * s + self.uv_offset.0, su * s + sv * c + self.uv_offset.1)
+    }
+
+    fn nearest(&self, level: usize, u: f64, v: f64) -> Color {
+        let l = &self.levels[level];
+        let x = (u * l.width as f64).floor() as i64;
+        let y = ((1.0 - v) * l.height as f64).floor() as i64;
+        self.texel(level, x, y)
+    }
+
+    fn bilinear(&self, level: usize, u: f64, v: f64) -> Color {
+        let l = &self.levels[level];
+        let x = u * l.width as f64 - 0.5;
+        let y = (1.0 - v) * l.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        (1.0 - fx) * (1.0 - fy) * self.texel(level, x0, y0)
+            + fx * (1.0 - fy) * self.texel(level, x0 + 1, y0)
+            + (1.0 - fx) * fy * self.texel(level, x0, y0 + 1)
+            + fx * fy * self.texel(level, x0 + 1, y0 + 1)
+    }
+
+    //footprintはuv空間での幅(テクセル単位でなく0..1単位)
+    fn filtered(&self, u: f64, v: f64, footprint: f64) -> Color {
+        let (u, v) = self.transform_uv(u, v);
+        match self.filter {
+            TextureFilter::Nearest => self.nearest(0, u, v),
+            TextureFilter::Bilinear => self.bilinear(0, u, v),
+            TextureFilter::Trilinear => {
+                let texels = footprint * self.width.max(self.height) as f64;
+                let max_level = (self.levels.len() - 1) as f64;
+                let lod = if texels > 1.0 { texels.log2().min(max_level) } else { 0.0 };
+                let l0 = lod.floor() as usize;
+                let l1 = (l0 + 1).min(self.levels.len() - 1);
+                let f = lod - l0 as f64;
+                (1.0 - f) * self.bilinear(l0, u, v) + f * self.bilinear(l1, u, v)
+            }
+        }
     }
 }
 impl Texture for ImageTexture {
     fn value(&self, u: f64, v: f64, _p: Vec3) -> Color {
-        let x = (u * self.width as f64) as i64;
-        let y = ((1.0 - v) * self.height as f64) as i64;
-        self.sample(x, y)
+        self.filtered(u, v, 0.0)
+    }
+    fn value_at(&self, hit: &HitInfo) -> Color {
+        //ワールド空間のフットプリントを接線の長さでuv空間に直す
+        let scale = self.uv_scale.0.abs().max(self.uv_scale.1.abs());
+        let du = hit.footprint / hit.dpdu.length().sqrt().max(EPS);
+        let dv = hit.footprint / hit.dpdv.length().sqrt().max(EPS);
+        self.filtered(hit.u, hit.v, du.max(dv) * scale)
+    }
+    fn alpha(&self, u: f64, v: f64, _p: Vec3) -> f64 {
+        let (u, v) = self.transform_uv(u, v);
+        let x = (u * self.width as f64).floor() as i64;
+        let y = ((1.0 - v) * self.height as f64).floor() as i64;
+        self.sample_alpha(x, y)
     }
 }
 
 pub struct DiffuseLight {
     pub emit: Box<dyn Texture>,
+    //色とは別の明るさの倍率
+    pub scale: f64,
+    //falseなら法線の向いている側だけ光る
+    pub two_sided: bool,
+    pub profile: EmissionProfile,
 }
 impl DiffuseLight {
     pub fn new(emit: Box<dyn Texture>) -> Self {
-        Self { emit }
+        Self {
+            emit,
+            scale: 1.0,
+            two_sided: true,
+            profile: EmissionProfile::Uniform,
+        }
+    }
+    //色温度(K)で光らせる 輝度はscaleで決める
+    pub fn blackbody(kelvin: f64, scale: f64) -> Self {
+        Self::new(Box::new(ColorTexture::new(blackbody(kelvin)))).with_scale(scale)
+    }
+    pub fn with_scale(self, scale: f64) -> Self {
+        Self { scale, ..self }
+    }
+    pub fn one_sided(self) -> Self {
+        Self { two_sided: false, ..self }
+    }
+    pub fn with_profile(self, profile: EmissionProfile) -> Self {
+        Self { profile, ..self }
     }
 }
 
@@ -112,18 +367,44 @@ impl Material for DiffuseLight {
         None
     }
     fn emitted(&self, ray: &Ray, hit: &HitInfo) -> Color {
-        self.emit.value(hit.u, hit.v, hit.p)
+        let wo = -ray.d.norm();
+        let mut cos_theta = wo.dot(&hit.n);
+        if cos_theta <= 0.0 {
+            if !self.two_sided {
+                return Color::zero();
+            }
+            cos_theta = -cos_theta;
+        }
+        let falloff = match self.profile {
+            EmissionProfile::Uniform => 1.0,
+            _ => self.profile.eval(cos_theta, emission_azimuth(hit, wo)),
+        };
+        self.emit.value_at(hit) * (self.scale * falloff)
+    }
+    fn is_emissive(&self) -> bool {
+        true
+    }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        Color::zero()
     }
 }
 
 pub struct ScatterInfo {
     pub ray: Ray,
     pub albedo: Color,
+    //散乱方向の確率密度 鏡面など評価できないものは0
+    pub pdf: f64,
 }
 
 impl ScatterInfo {
     pub fn new(ray: Ray, albedo: Vec3) -> Self {
-        Self { ray, albedo }
+        Self { ray, albedo, pdf: 0.0 }
+    }
+    pub fn with_pdf(self, pdf: f64) -> Self {
+        Self { pdf, ..self }
+    }
+    pub fn is_specular(&self) -> bool {
+        self.pdf == 0.0
     }
 }
 pub struct Lambertian {
@@ -136,10 +417,29 @@ impl Lambertian {
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
-        let target = hit.p + hit.n + Vec3::random_hemisphere();
-        let albedo = self.albedo.value(hit.u, hit.v, hit.p);
-        Some(ScatterInfo::new(Ray::new(hit.p, target - hit.p), albedo))
+    //法線+単位球面上の点でcosに比例した方向を選ぶ
+    fn scatter(&self, _ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        let mut d = hit.n + Vec3::random_unit();
+        if d.length() < EPS {
+            d = hit.n;
+        }
+        let d = d.norm();
+        let albedo = self.albedo.value_at(hit);
+        let pdf = d.dot(&hit.n).max(EPS) / PI;
+        Some(ScatterInfo::new(Ray::new(hit.p, d), albedo).with_pdf(pdf))
+    }
+    fn eval(&self, _ray: &Ray, hit: &HitInfo, wi: Vec3) -> Color {
+        let cos = wi.dot(&hit.n);
+        if cos <= 0.0 {
+            return Color::zero();
+        }
+        self.albedo.value_at(hit) * (cos / PI)
+    }
+    fn pdf(&self, _ray: &Ray, hit: &HitInfo, wi: Vec3) -> f64 {
+        wi.dot(&hit.n).max(0.0) / PI
+    }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.albedo.value_at(hit)
     }
 }
 
@@ -158,12 +458,15 @@ impl Material for Metal {
         let mut reflected = ray.d.norm().reflect(hit.n);
         reflected = reflected + self.fuzz*Vec3::random_hemisphere() ;
         if reflected.dot(&hit.n) > 0.0 {
-            let albedo = self.albedo.value(hit.u, hit.v, hit.p);
+            let albedo = self.albedo.value_at(hit);
             Some(ScatterInfo::new(Ray::new(hit.p, reflected), albedo))
         } else {
             None
         }
     }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.albedo.value_at(hit)
+    }
 }
 
 pub struct Dielectric {
@@ -195,13 +498,13 @@ impl Material for Dielectric {
                 )
             }
         };
-        if let Some(refracted) = (-ray.d ).refract(outward_normal, ni_over_nt) {
-            if Vec3::random_full().x > Self::schlick(cosine, self.ri) {
-                return Some(ScatterInfo::new(
-                    Ray::new(hit.p, refracted),
-                    Vec3::new(1.0, 1.0, 1.0),
-                ));
-            }
+        if let Some(refracted) = (-ray.d ).refract(outward_normal, ni_over_nt)
+            && Vec3::random_full().x > Self::schlick(cosine, self.ri)
+        {
+            return Some(ScatterInfo::new(
+                Ray::new(hit.p, refracted),
+                Vec3::new(1.0, 1.0, 1.0),
+            ));
         }
         Some(ScatterInfo::new(
             Ray::new(hit.p, reflected),
@@ -209,3 +512,147 @@ impl Material for Dielectric {
         ))
     }
 }
+
+//視線から見て幾何法線と表裏が逆になるシェーディング法線は視線と垂直な方向まで戻す
+fn shading_hit(ray: &Ray, hit: &HitInfo, ns: Vec3) -> HitInfo {
+    let wo = -ray.d.norm();
+    let side = if hit.n.dot(&wo) >= 0.0 { 1.0 } else { -1.0 };
+    let mut ns = ns;
+    let cos_o = ns.dot(&wo);
+    if cos_o * side < 0.01 {
+        ns = (ns + wo * (0.01 * side - cos_o)).norm();
+    }
+    HitInfo { n: ns, ..hit.clone() }
+}
+
+//シェーディング法線と幾何法線で表裏の判定が食い違う方向は光漏れになる
+fn leaks(ng: Vec3, ns: Vec3, d: Vec3) -> bool {
+    d.dot(&ns) * d.dot(&ng) <= 0.0
+}
+
+//シェーディング法線で散乱させる。幾何法線と食い違って光漏れしないよう補正・棄却する
+fn scatter_with_normal(
+    material: &dyn Material,
+    ray: &Ray,
+    hit: &HitInfo,
+    ns: Vec3,
+) -> Option<ScatterInfo> {
+    let shading = shading_hit(ray, hit, ns);
+    let scatter = material.scatter(ray, &shading)?;
+    if leaks(hit.n, shading.n, scatter.ray.d) {
+        return None;
+    }
+    Some(scatter)
+}
+
+fn eval_with_normal(material: &dyn Material, ray: &Ray, hit: &HitInfo, ns: Vec3, wi: Vec3) -> Color {
+    let shading = shading_hit(ray, hit, ns);
+    if leaks(hit.n, shading.n, wi) {
+        return Color::zero();
+    }
+    material.eval(ray, &shading, wi)
+}
+
+fn pdf_with_normal(material: &dyn Material, ray: &Ray, hit: &HitInfo, ns: Vec3, wi: Vec3) -> f64 {
+    let shading = shading_hit(ray, hit, ns);
+    if leaks(hit.n, shading.n, wi) {
+        return 0.0;
+    }
+    material.pdf(ray, &shading, wi)
+}
+
+//接線空間ノーマルマップ RGB(0..1)を(-1..1)のxyzとして解釈する
+pub struct NormalMap {
+    pub material: Arc<dyn Material>,
+    pub map: Box<dyn Texture>,
+    pub strength: f64,
+}
+
+impl NormalMap {
+    pub fn new(material: Arc<dyn Material>, map: Box<dyn Texture>) -> Self {
+        Self { material, map, strength: 1.0 }
+    }
+    pub fn with_strength(self, strength: f64) -> Self {
+        Self { strength, ..self }
+    }
+    fn shading_normal(&self, hit: &HitInfo) -> Vec3 {
+        let c = self.map.value_at(hit);
+        let tn = Vec3::new(
+            (2.0 * c.x - 1.0) * self.strength,
+            (2.0 * c.y - 1.0) * self.strength,
+            2.0 * c.z - 1.0,
+        );
+        let n = hit.n;
+        let t = (hit.dpdu - n * n.dot(&hit.dpdu)).norm();
+        let b = n % t;
+        //uvの向きが反転している面では従法線も反転させる
+        let b = if b.dot(&hit.dpdv) < 0.0 { -b } else { b };
+        (t * tn.x + b * tn.y + n * tn.z).norm()
+    }
+}
+
+impl Material for NormalMap {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        scatter_with_normal(self.material.as_ref(), ray, hit, self.shading_normal(hit))
+    }
+    fn eval(&self, ray: &Ray, hit: &HitInfo, wi: Vec3) -> Color {
+        eval_with_normal(self.material.as_ref(), ray, hit, self.shading_normal(hit), wi)
+    }
+    fn pdf(&self, ray: &Ray, hit: &HitInfo, wi: Vec3) -> f64 {
+        pdf_with_normal(self.material.as_ref(), ray, hit, self.shading_normal(hit), wi)
+    }
+    fn emitted(&self, ray: &Ray, hit: &HitInfo) -> Color {
+        self.material.emitted(ray, hit)
+    }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.material.albedo(hit)
+    }
+    fn normal_at(&self, hit: &HitInfo) -> Vec3 {
+        self.shading_normal(hit)
+    }
+}
+
+//高さテクスチャ(輝度)によるバンプマップ
+pub struct BumpMap {
+    pub material: Arc<dyn Material>,
+    pub height: Box<dyn Texture>,
+    pub scale: f64,
+}
+
+impl BumpMap {
+    pub fn new(material: Arc<dyn Material>, height: Box<dyn Texture>, scale: f64) -> Self {
+        Self { material, height, scale }
+    }
+    fn shading_normal(&self, hit: &HitInfo) -> Vec3 {
+        const DELTA: f64 = 0.0005;
+        let h = |u: f64, v: f64, p: Vec3| self.height.value(u, v, p).luminance() * self.scale;
+        let h0 = h(hit.u, hit.v, hit.p);
+        let dhdu = (h(hit.u + DELTA, hit.v, hit.p + hit.dpdu * DELTA) - h0) / DELTA;
+        let dhdv = (h(hit.u, hit.v + DELTA, hit.p + hit.dpdv * DELTA) - h0) / DELTA;
+        let dpdu = hit.dpdu + hit.n * dhdu;
+        let dpdv = hit.dpdv + hit.n * dhdv;
+        let ns = (dpdu % dpdv).norm();
+        if ns.dot(&hit.n) < 0.0 { -ns } else { ns }
+    }
+}
+
+impl Material for BumpMap {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        scatter_with_normal(self.material.as_ref(), ray, hit, self.shading_normal(hit))
+    }
+    fn eval(&self, ray: &Ray, hit: &HitInfo, wi: Vec3) -> Color {
+        eval_with_normal(self.material.as_ref(), ray, hit, self.shading_normal(hit), wi)
+    }
+    fn pdf(&self, ray: &Ray, hit: &HitInfo, wi: Vec3) -> f64 {
+        pdf_with_normal(self.material.as_ref(), ray, hit, self.shading_normal(hit), wi)
+    }
+    fn emitted(&self, ray: &Ray, hit: &HitInfo) -> Color {
+        self.material.emitted(ray, hit)
+    }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.material.albedo(hit)
+    }
+    fn normal_at(&self, hit: &HitInfo) -> Vec3 {
+        self.shading_normal(hit)
+    }
+}
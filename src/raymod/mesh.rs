@@ -0,0 +1,154 @@
+use crate::raymod::*;
+
+use std::sync::{Arc, OnceLock};
+
+//三角形ポリゴン uvは頂点ごとに指定、省略時は(0,0),(1,0),(0,1)
+pub struct Triangle {
+    pub p: [Vec3; 3],
+    pub uv: [(f64, f64); 3],
+    pub material: Arc<dyn Material>,
+    light: OnceLock<usize>,
+}
+
+impl Triangle {
+    pub fn new(p0: Vec3, p1: Vec3, p2: Vec3, material: Arc<dyn Material>) -> Self {
+        Self {
+            p: [p0, p1, p2],
+            uv: [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)],
+            material,
+            light: OnceLock::new(),
+        }
+    }
+    pub fn with_uv(self, uv0: (f64, f64), uv1: (f64, f64), uv2: (f64, f64)) -> Self {
+        Self { uv: [uv0, uv1, uv2], ..self }
+    }
+    pub fn normal(&self) -> Vec3 {
+        ((self.p[1] - self.p[0]) % (self.p[2] - self.p[0])).norm()
+    }
+    //uvの変化から接線を求める、uvが縮退していたら法線から適当に作る
+    pub(crate) fn tangent(&self, n: Vec3) -> (Vec3, Vec3) {
+        let dp1 = self.p[1] - self.p[0];
+        let dp2 = self.p[2] - self.p[0];
+        let (du1, dv1) = (self.uv[1].0 - self.uv[0].0, self.uv[1].1 - self.uv[0].1);
+        let (du2, dv2) = (self.uv[2].0 - self.uv[0].0, self.uv[2].1 - self.uv[0].1);
+        let det = du1 * dv2 - dv1 * du2;
+        if det.abs() < EPS {
+            return n.orthonormal_basis();
+        }
+        let inv = 1.0 / det;
+        let dpdu = (dv2 * dp1 - dv1 * dp2) * inv;
+        let dpdv = (du1 * dp2 - du2 * dp1) * inv;
+        (dpdu, dpdv)
+    }
+}
+
+impl Shape for Triangle {
+    //Möller–Trumbore
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        let e1 = self.p[1] - self.p[0];
+        let e2 = self.p[2] - self.p[0];
+        let pv = ray.d % e2;
+        let det = e1.dot(&pv);
+        if det.abs() < EPS * EPS {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let tv = ray.o - self.p[0];
+        let b1 = tv.dot(&pv) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let qv = tv % e1;
+        let b2 = ray.d.dot(&qv) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        let t = e2.dot(&qv) * inv_det;
+        if t < t0 || t > t1 {
+            return None;
+        }
+        let b0 = 1.0 - b1 - b2;
+        let u = b0 * self.uv[0].0 + b1 * self.uv[1].0 + b2 * self.uv[2].0;
+        let v = b0 * self.uv[0].1 + b1 * self.uv[1].1 + b2 * self.uv[2].1;
+        let n = self.normal();
+        let (dpdu, dpdv) = self.tangent(n);
+        Some(
+            HitInfo::new(t, ray.at(t), n, Arc::clone(&self.material), u, v)
+                .with_tangent(dpdu, dpdv)
+                .with_light(self.light.get().copied()),
+        )
+    }
+
+    fn bounding_box(&self) -> Option<AABB> {
+        let mut min = self.p[0];
+        let mut max = self.p[0];
+        for p in &self.p[1..] {
+            for a in 0..3 {
+                min[a] = min[a].min(p[a]);
+                max[a] = max[a].max(p[a]);
+            }
+        }
+        //軸に平行な三角形でも厚みを持たせる
+        for a in 0..3 {
+            min[a] -= EPS10;
+            max[a] += EPS10;
+        }
+        Some(AABB { min, max })
+    }
+
+    fn collect_lights(&self, xf: &Transform, lights: &mut Vec<Box<dyn Light>>) {
+        if self.material.is_emissive() {
+            let _ = self.light.set(lights.len());
+            let tri = Triangle::new(self.p[0], self.p[1], self.p[2], Arc::clone(&self.material))
+                .with_uv(self.uv[0], self.uv[1], self.uv[2]);
+            lights.push(Box::new(AreaLight::new(AreaShape::Triangle(tri), Arc::clone(&self.material), *xf)));
+        }
+    }
+    fn collect_materials(&self, materials: &mut Vec<Arc<dyn Material>>) {
+        materials.push(Arc::clone(&self.material));
+    }
+}
+
+//インデックス付き三角形メッシュ 内部はBVH
+pub struct Mesh {
+    bvh: BVH,
+}
+
+impl Mesh {
+    pub fn new(
+        positions: &[Vec3],
+        uvs: Option<&[(f64, f64)]>,
+        indices: &[[usize; 3]],
+        material: Arc<dyn Material>,
+    ) -> Self {
+        let mut triangles: Vec<Box<dyn Shape>> = Vec::new();
+        for idx in indices {
+            let mut tri = Triangle::new(
+                positions[idx[0]],
+                positions[idx[1]],
+                positions[idx[2]],
+                Arc::clone(&material),
+            );
+            if let Some(uvs) = uvs {
+                tri = tri.with_uv(uvs[idx[0]], uvs[idx[1]], uvs[idx[2]]);
+            }
+            triangles.push(Box::new(tri));
+        }
+        Self { bvh: BVH::new(triangles) }
+    }
+}
+
+impl Shape for Mesh {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        self.bvh.hit(ray, t0, t1)
+    }
+    fn bounding_box(&self) -> Option<AABB> {
+        self.bvh.bounding_box()
+    }
+    fn collect_lights(&self, xf: &Transform, lights: &mut Vec<Box<dyn Light>>) {
+        self.bvh.collect_lights(xf, lights);
+    }
+    fn collect_materials(&self, materials: &mut Vec<Arc<dyn Material>>) {
+        self.bvh.collect_materials(materials);
+    }
+}
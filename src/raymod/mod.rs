@@ -1,22 +1,63 @@
 
+mod adaptive;
+mod aov;
+mod bdpt;
 mod bvh;
+mod checkpoint;
+mod denoise;
+mod distribution;
+mod emission;
+mod envlight;
+mod film;
+mod integrator;
+mod light;
+mod lightbvh;
 mod material;
+mod mesh;
 mod optarg;
+mod output;
 mod rayunit;
+mod sampler;
 mod scene;
+mod sky;
+mod sppm;
+mod tonemap;
 mod vec3;
+mod postfx;
+mod progressive;
 mod quat;
 
+pub use self::adaptive::*;
+pub use self::aov::*;
+pub use self::bdpt::*;
 pub use self::bvh::*;
+pub use self::checkpoint::*;
+pub use self::denoise::*;
+pub use self::distribution::*;
+pub use self::emission::*;
+pub use self::envlight::*;
+pub use self::film::*;
+pub use self::integrator::*;
+pub use self::light::*;
+pub use self::lightbvh::*;
 pub use self::material::*;
+pub use self::mesh::*;
 pub use self::optarg::*;
+pub use self::output::*;
 pub use self::rayunit::*;
+pub use self::sampler::*;
 pub use self::scene::*;
+pub use self::sky::*;
+pub use self::sppm::*;
+pub use self::tonemap::*;
 pub use self::vec3::*;
+pub use self::postfx::*;
+pub use self::progressive::*;
 pub use self::quat::*;
 
 pub const EPS: f64 = 1e-6;
 pub const EPS10: f64 = 1e-4;
+#[allow(dead_code)]
 pub const FRAC_SQRT_3: f64 = 1.732050807568877293527446341505872367;
 pub const WIDE_ASPECT: f64 = 16.0 / 9.0;
 pub const SQUARE_ASPECT:f64 =1.0;
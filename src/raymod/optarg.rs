@@ -1,5 +1,48 @@
+use crate::raymod::{
+    Aov, Bloom, ColorPipeline, DebugMode, Filter, FilterKind, Glare, LightSelection, PostEffects, SamplerKind, ToneMapper,
+};
 use getopts::Options;
 use std::process;
+use std::str::FromStr;
+
+//描画に使う積分器
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntegratorKind {
+    //次イベント推定付きのパストレーシング
+    Path,
+    //双方向パストレーシング
+    Bdpt,
+    //確率的プログレッシブフォトンマッピング
+    Sppm,
+    //直接光だけ
+    Direct,
+    //アンビエントオクルージョン
+    Ao,
+    //形状や構造を色で見るデバッグ表示
+    Debug(DebugMode),
+}
+
+impl FromStr for IntegratorKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(IntegratorKind::Path),
+            "bdpt" => Ok(IntegratorKind::Bdpt),
+            "sppm" => Ok(IntegratorKind::Sppm),
+            "direct" => Ok(IntegratorKind::Direct),
+            "ao" => Ok(IntegratorKind::Ao),
+            "normal" => Ok(IntegratorKind::Debug(DebugMode::Normal)),
+            "uv" => Ok(IntegratorKind::Debug(DebugMode::Uv)),
+            "depth" => Ok(IntegratorKind::Debug(DebugMode::Depth)),
+            "material" => Ok(IntegratorKind::Debug(DebugMode::MaterialId)),
+            "bvh-cost" => Ok(IntegratorKind::Debug(DebugMode::BvhCost)),
+            _ => Err(format!(
+                "unknown integrator: {} (path|bdpt|sppm|direct|ao|normal|uv|depth|material|bvh-cost)",
+                s
+            )),
+        }
+    }
+}
 
 // 引数を格納する構造体
 #[derive(Debug)]
@@ -9,6 +52,87 @@ pub struct Args {
     pub w: usize,
     pub m: usize,
     pub output: String,
+    pub envmap: Option<String>,
+    pub env_rotation: f64,
+    pub env_intensity: f64,
+    pub sky: bool,
+    pub sun: Option<(f64, f64)>,
+    pub turbidity: f64,
+    pub ground_albedo: f64,
+    pub light_sampler: LightSelection,
+    //省略したらシーンの既定値
+    pub max_depth: Option<i64>,
+    pub rr_depth: i64,
+    pub integrator: IntegratorKind,
+    pub sampler: SamplerKind,
+    pub seed: u64,
+    pub filter: Filter,
+    pub adaptive: Option<f64>,
+    pub max_spp: Option<usize>,
+    pub spp_heatmap: Option<String>,
+    pub progressive: bool,
+    pub pass_spp: usize,
+    pub snapshot_interval: Option<f64>,
+    pub snapshot_passes: Option<usize>,
+    pub checkpoint: Option<String>,
+    pub checkpoint_interval: f64,
+    pub resume: Option<String>,
+    pub time_limit: Option<f64>,
+    pub target_noise: Option<f64>,
+    pub photons: Option<usize>,
+    pub photon_radius: Option<f64>,
+    pub ao_distance: Option<f64>,
+    pub aovs: Vec<Aov>,
+    pub half: bool,
+    //色の処理 省略した項目はシーンの既定値
+    pub exposure: Option<f64>,
+    pub white_balance: Option<f64>,
+    pub tonemap: Option<ToneMapper>,
+    pub white_point: Option<f64>,
+    pub denoise: bool,
+    pub post: PostEffects,
+    pub post_float: bool,
+}
+
+impl Args {
+    //シーンの既定の色の処理に、コマンドラインで指定した項目を上書きする
+    pub fn pipeline(&self, base: ColorPipeline) -> ColorPipeline {
+        base.with_exposure(self.exposure.unwrap_or(base.exposure))
+            .with_white_balance(self.white_balance.or(base.white_balance))
+            .with_tonemap(self.tonemap.unwrap_or(base.tonemap))
+            .with_white_point(self.white_point.or(base.white_point))
+    }
+
+    //溜める値に関わる設定 チェックポイントの照合に使う
+    //出力先・色の処理・途中経過の書き出し方は後から変えてよいので含めない
+    pub fn render_settings(&self, pass_spp: usize) -> String {
+        format!(
+            "s={} w={} m={} envmap={:?} env={} {} sky={} sun={:?} {} {} lights={:?} depth={:?} {} integrator={:?} \
+             sampler={:?} seed={} filter={:?} adaptive={:?} {:?} pass={} ao={:?} aov={}",
+            self.s,
+            self.w,
+            self.m,
+            self.envmap,
+            self.env_rotation,
+            self.env_intensity,
+            self.sky,
+            self.sun,
+            self.turbidity,
+            self.ground_albedo,
+            self.light_sampler,
+            self.max_depth,
+            self.rr_depth,
+            self.integrator,
+            self.sampler,
+            self.seed,
+            self.filter,
+            self.adaptive,
+            self.max_spp,
+            pass_spp,
+            self.ao_distance,
+            !self.aovs.is_empty() || self.denoise,
+        )
+    }
 }
 
 fn print_usage(exe_name: &str, opts: &Options) {
@@ -26,7 +150,74 @@ pub fn parameters() -> Args {
     opts.optopt("s", "samples", "sampling number", "1..etc");
     opts.optopt("w", "width", "screen width", "ex)768");
     opts.optopt("m", "model", "model number", "0..9");
-    opts.optopt("o", "output", "set output file name", "[FILE]");
+    opts.optopt("o", "output", "set output file name (.exr/.hdr/.pfm keep linear radiance)", "[FILE]");
+    opts.optflag("", "half", "write 16bit half floats to .exr");
+    opts.optopt("", "exposure", "exposure compensation in stops for 8bit output (default: per scene)", "0");
+    opts.optopt(
+        "",
+        "white-balance",
+        "color temperature of the light that should become white (default: per scene)",
+        "6500",
+    );
+    opts.optopt(
+        "",
+        "tonemap",
+        "tone mapping operator for 8bit output (default: per scene)",
+        "clamp|reinhard|reinhard-ext|hable|aces|agx",
+    );
+    opts.optopt("", "white-point", "luminance mapped to white by reinhard-ext (default: image maximum)", "W");
+    opts.optopt("", "envmap", "equirectangular environment map (.hdr/.exr etc)", "[FILE]");
+    opts.optopt("", "env-rotation", "rotate environment map around y axis", "degrees");
+    opts.optopt("", "env-intensity", "scale environment map radiance", "1.0");
+    opts.optflag("", "sky", "use gradient sky as background");
+    opts.optopt("", "sun", "use physical sky with sun at elevation,azimuth", "45,30");
+    opts.optopt("", "turbidity", "atmospheric turbidity of physical sky", "2..10");
+    opts.optopt("", "ground-albedo", "ground albedo of physical sky", "0.3");
+    opts.optopt("", "light-sampler", "how to pick a light for direct lighting", "bvh|power");
+    opts.optopt("", "max-depth", "maximum number of bounces (default: per scene)", "32");
+    opts.optopt("", "rr-depth", "start russian roulette after this many bounces", "3");
+    opts.optopt(
+        "",
+        "integrator",
+        "rendering algorithm or debug view",
+        "path|bdpt|sppm|direct|ao|normal|uv|depth|material|bvh-cost",
+    );
+    opts.optopt(
+        "",
+        "sampler",
+        "sample pattern for camera, lens and bounces",
+        "independent|stratified|halton|sobol|bluenoise",
+    );
+    opts.optopt("", "filter", "pixel reconstruction filter", "box|tent|gaussian|mitchell|lanczos");
+    opts.optopt("", "filter-radius", "filter radius in pixels (default: depends on filter)", "R");
+    opts.optopt("", "adaptive", "keep sampling pixels whose relative error exceeds this", "0.05");
+    opts.optopt("", "max-spp", "sample limit per pixel for --adaptive (default: 8x the -s samples)", "N");
+    opts.optopt("", "spp-heatmap", "write per-pixel sample counts as a heatmap png", "[FILE]");
+    opts.optflag("", "progressive", "render the whole frame in passes and write snapshots to the output file");
+    opts.optopt("", "pass-spp", "samples per pixel in each progressive pass", "4");
+    opts.optopt("", "snapshot-interval", "seconds between progressive snapshots (default: 10)", "SECONDS");
+    opts.optopt("", "snapshot-passes", "passes between progressive snapshots", "N");
+    opts.optopt("", "checkpoint", "periodically save the render state to this file", "[FILE]");
+    opts.optopt("", "checkpoint-interval", "seconds between checkpoints", "60");
+    opts.optopt("", "resume", "continue the render saved in a checkpoint file", "[FILE]");
+    opts.optopt("", "time-limit", "keep rendering passes until this many seconds have passed", "SECONDS");
+    opts.optopt("", "target-noise", "keep rendering passes until the estimated relative noise drops to this", "0.02");
+    opts.optopt("", "seed", "random seed; the same seed gives the same image", "0");
+    opts.optopt("", "photons", "photons per sppm iteration (default: pixel count)", "N");
+    opts.optopt("", "photon-radius", "initial sppm gather radius (default: from pixel footprint)", "R");
+    opts.optopt("", "ao-distance", "occlusion distance for ao (default: 1/10 of scene size)", "D");
+    opts.optopt(
+        "",
+        "aov",
+        "also write these passes next to the output",
+        "albedo,normal,depth,id,direct,indirect,emission|all",
+    );
+    opts.optflag("", "denoise", "filter the image guided by albedo, normal and depth after rendering");
+    opts.optopt("", "bloom", "bloom above a luminance threshold", "threshold,intensity,levels(1,0.1,6)");
+    opts.optopt("", "glare", "star shaped glare", "threshold,intensity,streaks,angle,decay(1,0.05,6,15,0.95)");
+    opts.optopt("", "chromatic-aberration", "lateral color shift at the image corners", "0.005");
+    opts.optopt("", "vignette", "darkening at the image corners", "0.3");
+    opts.optflag("", "post-float", "also apply the post effects to .exr/.hdr/.pfm output");
     opts.optflag("h", "help", "print this help");
 
     // パース
@@ -56,12 +247,175 @@ pub fn parameters() -> Args {
         .parse()
         .unwrap();
     let output = matches.opt_str("o").unwrap_or("image.png".to_string());
+    let half = matches.opt_present("half");
+    let denoise = matches.opt_present("denoise");
+    let post_float = matches.opt_present("post-float");
+    //カンマ区切りの数値 省略した後ろの値は既定値を使う
+    let values = |name: &str, defaults: &[f64]| {
+        matches.opt_str(name).map(|s| {
+            let v: Vec<f64> = s.split(',').map(|x| x.trim().parse().unwrap()).collect();
+            (0..defaults.len()).map(|i| *v.get(i).unwrap_or(&defaults[i])).collect::<Vec<f64>>()
+        })
+    };
+    let post = PostEffects {
+        bloom: values("bloom", &[1.0, 0.1, 6.0]).map(|v| Bloom { threshold: v[0], intensity: v[1], levels: v[2] as usize }),
+        glare: values("glare", &[1.0, 0.05, 6.0, 15.0, 0.95]).map(|v| Glare {
+            threshold: v[0],
+            intensity: v[1],
+            streaks: v[2] as usize,
+            angle: v[3],
+            decay: v[4],
+        }),
+        chromatic_aberration: matches.opt_str("chromatic-aberration").map_or(0.0, |s| s.parse().unwrap()),
+        vignette: matches.opt_str("vignette").map_or(0.0, |s| s.parse().unwrap()),
+    };
+    let tonemap = matches
+        .opt_str("tonemap")
+        .map(|s| s.parse().unwrap_or_else(|e: String| panic!("{}", e)));
+    let exposure = matches.opt_str("exposure").map(|s| s.parse().unwrap());
+    let white_balance = matches.opt_str("white-balance").map(|s| s.parse().unwrap());
+    let white_point = matches.opt_str("white-point").map(|s| s.parse().unwrap());
+    let envmap = matches.opt_str("envmap");
+    let env_rotation = matches
+        .opt_str("env-rotation")
+        .unwrap_or("0".to_string())
+        .parse()
+        .unwrap();
+    let env_intensity = matches
+        .opt_str("env-intensity")
+        .unwrap_or("1".to_string())
+        .parse()
+        .unwrap();
+    let sky = matches.opt_present("sky");
+    let sun = matches.opt_str("sun").map(|s| {
+        let v: Vec<f64> = s.split(',').map(|x| x.trim().parse().unwrap()).collect();
+        (v[0], *v.get(1).unwrap_or(&0.0))
+    });
+    let turbidity = matches
+        .opt_str("turbidity")
+        .unwrap_or("2.5".to_string())
+        .parse()
+        .unwrap();
+    let ground_albedo = matches
+        .opt_str("ground-albedo")
+        .unwrap_or("0.3".to_string())
+        .parse()
+        .unwrap();
+    let light_sampler = matches
+        .opt_str("light-sampler")
+        .unwrap_or("bvh".to_string())
+        .parse()
+        .unwrap_or_else(|e: String| panic!("{}", e));
+    let max_depth = matches.opt_str("max-depth").map(|s| s.parse::<i64>().unwrap());
+    //0はパストレーサーでは真っ黒、BDPTでは光源だけになって意味がそろわないので受け付けない
+    if max_depth.is_some_and(|d| d < 1) {
+        eprintln!("--max-depth must be at least 1");
+        process::exit(1);
+    }
+    let rr_depth = matches
+        .opt_str("rr-depth")
+        .unwrap_or("3".to_string())
+        .parse()
+        .unwrap();
+    let integrator = matches
+        .opt_str("integrator")
+        .unwrap_or("path".to_string())
+        .parse()
+        .unwrap_or_else(|e: String| panic!("{}", e));
+    let sampler = matches
+        .opt_str("sampler")
+        .unwrap_or("stratified".to_string())
+        .parse()
+        .unwrap_or_else(|e: String| panic!("{}", e));
+    let seed = matches
+        .opt_str("seed")
+        .unwrap_or("0".to_string())
+        .parse()
+        .unwrap();
+    let filter_kind: FilterKind = matches
+        .opt_str("filter")
+        .unwrap_or("box".to_string())
+        .parse()
+        .unwrap_or_else(|e: String| panic!("{}", e));
+    let filter = match matches.opt_str("filter-radius") {
+        Some(r) => Filter::new(filter_kind).with_radius(r.parse().unwrap()),
+        None => Filter::new(filter_kind),
+    };
+    let adaptive = matches.opt_str("adaptive").map(|s| s.parse().unwrap());
+    let max_spp = matches.opt_str("max-spp").map(|s| s.parse().unwrap());
+    let spp_heatmap = matches.opt_str("spp-heatmap");
+    let progressive = matches.opt_present("progressive");
+    let pass_spp = matches
+        .opt_str("pass-spp")
+        .unwrap_or("4".to_string())
+        .parse()
+        .unwrap();
+    let snapshot_interval = matches.opt_str("snapshot-interval").map(|s| s.parse().unwrap());
+    let snapshot_passes = matches.opt_str("snapshot-passes").map(|s| s.parse().unwrap());
+    let checkpoint = matches.opt_str("checkpoint");
+    let checkpoint_interval = matches
+        .opt_str("checkpoint-interval")
+        .unwrap_or("60".to_string())
+        .parse()
+        .unwrap();
+    let resume = matches.opt_str("resume");
+    let time_limit = matches.opt_str("time-limit").map(|s| s.parse().unwrap());
+    let target_noise = matches.opt_str("target-noise").map(|s| s.parse().unwrap());
+    let photons = matches.opt_str("photons").map(|s| s.parse().unwrap());
+    let photon_radius = matches.opt_str("photon-radius").map(|s| s.parse().unwrap());
+    let ao_distance = matches.opt_str("ao-distance").map(|s| s.parse().unwrap());
+    let aovs = matches
+        .opt_str("aov")
+        .map_or(Ok(Vec::new()), |s| Aov::parse_list(&s))
+        .unwrap_or_else(|e: String| panic!("{}", e));
     // 位置引数の取得
     //    let repeat = matches.free[0].clone().parse::<usize>().unwrap_or_else(|f| panic!("{}",f.to_string()));
 
     // 構造体の生成
-    let ret = Args { s, w, m, output };
-    return ret;
+    Args {
+        s,
+        w,
+        m,
+        output,
+        envmap,
+        env_rotation,
+        env_intensity,
+        sky,
+        sun,
+        turbidity,
+        ground_albedo,
+        light_sampler,
+        max_depth,
+        rr_depth,
+        integrator,
+        sampler,
+        seed,
+        filter,
+        adaptive,
+        max_spp,
+        spp_heatmap,
+        progressive,
+        pass_spp,
+        snapshot_interval,
+        snapshot_passes,
+        checkpoint,
+        checkpoint_interval,
+        resume,
+        time_limit,
+        target_noise,
+        photons,
+        photon_radius,
+        ao_distance,
+        aovs,
+        half,
+        exposure,
+        white_balance,
+        tonemap,
+        white_point,
+        denoise,
+        post,
+        post_float,
+    }
 }
 #[allow(dead_code)]
 fn test() {
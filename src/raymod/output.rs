@@ -0,0 +1,146 @@
+use crate::raymod::*;
+
+use exr::prelude::{AnyChannel, AnyChannels, Encoding, FlatSamples, Image, Layer, LayerAttributes, SmallVec, WritableImage, f16};
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+//出力の画像形式 -oの拡張子で決める
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    //8bitでガンマをかける png, jpgなど
+    Display,
+    //リニアな放射輝度をそのまま保存する
+    Exr,
+    Hdr,
+    Pfm,
+}
+
+impl ImageFormat {
+    pub fn from_path(path: &str) -> Self {
+        let ext = Path::new(path).extension().and_then(|s| s.to_str()).unwrap_or("").to_ascii_lowercase();
+        match ext.as_str() {
+            "exr" => ImageFormat::Exr,
+            "hdr" => ImageFormat::Hdr,
+            "pfm" => ImageFormat::Pfm,
+            _ => ImageFormat::Display,
+        }
+    }
+    pub fn is_float(&self) -> bool {
+        *self != ImageFormat::Display
+    }
+}
+
+//保存の設定
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OutputOptions {
+    //EXRを16bit浮動小数点で書く
+    pub half: bool,
+    //8bit画像にするときの色の処理 浮動小数点の形式には使わない
+    pub pipeline: ColorPipeline,
+}
+
+//EXRに書くチャンネル 名前と画素ごとの値
+struct ExrChannel {
+    name: String,
+    values: Vec<f32>,
+}
+
+fn rgb_channels(prefix: &str, image: &[Color]) -> Vec<ExrChannel> {
+    ["R", "G", "B"]
+        .iter()
+        .enumerate()
+        .map(|(i, c)| ExrChannel {
+            name: format!("{}{}", prefix, c),
+            values: image.iter().map(|p| p[i] as f32).collect(),
+        })
+        .collect()
+}
+
+//一つのEXRにまとめて書く halfなら16bit浮動小数点
+fn save_exr(filename: &str, channels: Vec<ExrChannel>, width: usize, height: usize, half: bool) {
+    let list: SmallVec<[AnyChannel<FlatSamples>; 4]> = channels
+        .into_iter()
+        .map(|c| {
+            let samples = if half {
+                FlatSamples::F16(c.values.into_iter().map(f16::from_f32).collect())
+            } else {
+                FlatSamples::F32(c.values)
+            };
+            AnyChannel::new(c.name.as_str(), samples)
+        })
+        .collect();
+    let layer = Layer::new(
+        (width, height),
+        LayerAttributes::named("image"),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(list),
+    );
+    Image::from_layer(layer).write().to_file(filename).unwrap();
+}
+
+//Radiance HDR(RGBE)
+fn save_hdr(filename: &str, image: &[Color], width: usize, height: usize) {
+    let data = image.iter().flat_map(|c| [c.x as f32, c.y as f32, c.z as f32]).collect();
+    let buf = image::Rgb32FImage::from_raw(width as u32, height as u32, data).unwrap();
+    buf.save(filename).unwrap();
+}
+
+//Portable Float Map 行は下から上に並べる
+fn save_pfm(filename: &str, image: &[Color], width: usize, height: usize) {
+    let mut f = BufWriter::new(fs::File::create(filename).unwrap());
+    //負のスケールはリトルエンディアン
+    write!(f, "PF\n{} {}\n-1.0\n", width, height).unwrap();
+    for row in image.chunks(width).rev() {
+        for c in row {
+            for v in [c.x, c.y, c.z] {
+                f.write_all(&(v as f32).to_le_bytes()).unwrap();
+            }
+        }
+    }
+}
+
+//一枚の画像を拡張子に合わせて保存する
+pub fn save_image(filename: &str, image: &[Color], width: usize, height: usize, opts: &OutputOptions) {
+    match ImageFormat::from_path(filename) {
+        ImageFormat::Display => save_png_file(filename, opts.pipeline.apply(image), width, height),
+        ImageFormat::Exr => save_exr(filename, rgb_channels("", image), width, height, opts.half),
+        ImageFormat::Hdr => save_hdr(filename, image, width, height),
+        ImageFormat::Pfm => save_pfm(filename, image, width, height),
+    }
+}
+
+//本画像とAOVを保存する EXRならAOVは同じファイルのalbedo.Rのようなチャンネルになる
+pub fn save_render(
+    filename: &str,
+    image: &[Color],
+    aovs: &[Aov],
+    aov_pixels: &[AovSample],
+    width: usize,
+    height: usize,
+    opts: &OutputOptions,
+) {
+    if ImageFormat::from_path(filename) != ImageFormat::Exr || aovs.is_empty() {
+        save_image(filename, image, width, height, opts);
+        save_aovs(filename, aovs, aov_pixels, width, height, opts);
+        return;
+    }
+    let mut channels = rgb_channels("", image);
+    for &aov in aovs {
+        let layer: Vec<Color> = aov_pixels.iter().map(|p| p.get(aov)).collect();
+        match aov {
+            //一成分だけのものは慣例の名前にする
+            Aov::Depth => channels.push(ExrChannel {
+                name: "Z".to_string(),
+                values: layer.iter().map(|c| c.x as f32).collect(),
+            }),
+            Aov::ObjectId => channels.push(ExrChannel {
+                name: "id".to_string(),
+                values: layer.iter().map(|c| c.x as f32).collect(),
+            }),
+            _ => channels.extend(rgb_channels(&format!("{}.", aov.name()), &layer)),
+        }
+        println!("aov {} -> {}", aov.name(), filename);
+    }
+    save_exr(filename, channels, width, height, opts.half);
+}
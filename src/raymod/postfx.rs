@@ -0,0 +1,208 @@
+use crate::raymod::*;
+
+use rayon::prelude::*;
+use std::f64::consts::PI;
+
+//レンズ・フィルム風の効果 1を超える明るさが残っている浮動小数点の画像にトーンマップの前にかける
+
+//画面外は端の画素を使う
+fn fetch(image: &[Color], width: usize, height: usize, x: i64, y: i64) -> Color {
+    let x = x.clamp(0, width as i64 - 1) as usize;
+    let y = y.clamp(0, height as i64 - 1) as usize;
+    image[y * width + x]
+}
+
+//画素の中心を整数座標とするバイリニア補間
+fn bilinear(image: &[Color], width: usize, height: usize, x: f64, y: f64) -> Color {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let top = fetch(image, width, height, x0, y0) * (1.0 - tx) + fetch(image, width, height, x0 + 1, y0) * tx;
+    let bottom = fetch(image, width, height, x0, y0 + 1) * (1.0 - tx) + fetch(image, width, height, x0 + 1, y0 + 1) * tx;
+    top * (1.0 - ty) + bottom * ty
+}
+
+//閾値を超えた分だけ残す 色相は保つ
+fn bright_pass(image: &[Color], threshold: f64) -> Vec<Color> {
+    image
+        .iter()
+        .map(|&c| {
+            let l = c.luminance();
+            if l <= threshold { Color::zero() } else { c * ((l - threshold) / l) }
+        })
+        .collect()
+}
+
+//1,4,6,4,1の分離可能なガウスぼかし
+fn blur(image: &[Color], width: usize, height: usize) -> Vec<Color> {
+    const K: [f64; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+    let pass = |src: &[Color], dx: i64, dy: i64| -> Vec<Color> {
+        (0..width * height)
+            .into_par_iter()
+            .map(|p| {
+                let (x, y) = ((p % width) as i64, (p / width) as i64);
+                K.iter().enumerate().fold(Color::zero(), |acc, (i, k)| {
+                    let o = i as i64 - 2;
+                    acc + fetch(src, width, height, x + o * dx, y + o * dy) * *k
+                })
+            })
+            .collect()
+    };
+    pass(&pass(image, 1, 0), 0, 1)
+}
+
+//ぼかしてから半分の大きさにする
+fn downsample(image: &[Color], width: usize, height: usize) -> (Vec<Color>, usize, usize) {
+    let blurred = blur(image, width, height);
+    let (w, h) = (width.div_ceil(2), height.div_ceil(2));
+    let out = (0..w * h)
+        .map(|p| fetch(&blurred, width, height, 2 * (p % w) as i64, 2 * (p / w) as i64))
+        .collect();
+    (out, w, h)
+}
+
+//閾値付きブルーム 明るい部分をガウシアンピラミッドで何段階にもぼかして足す
+#[derive(Debug, Clone, Copy)]
+pub struct Bloom {
+    //これより明るい輝度だけがにじむ
+    pub threshold: f64,
+    pub intensity: f64,
+    //ピラミッドの段数 多いほど遠くまで広がる
+    pub levels: usize,
+}
+
+impl Bloom {
+    //足し込むにじみだけを返す
+    pub fn glow(&self, image: &[Color], width: usize, height: usize) -> Vec<Color> {
+        let mut level = bright_pass(image, self.threshold);
+        let (mut w, mut h) = (width, height);
+        let mut glow = vec![Color::zero(); width * height];
+        for _ in 0..self.levels.max(1) {
+            (level, w, h) = downsample(&level, w, h);
+            let blurred = blur(&level, w, h);
+            //各段を元の解像度に戻して足す
+            let (sx, sy) = (w as f64 / width as f64, h as f64 / height as f64);
+            glow.par_iter_mut().enumerate().for_each(|(p, g)| {
+                let (x, y) = ((p % width) as f64 + 0.5, (p / width) as f64 + 0.5);
+                *g = *g + bilinear(&blurred, w, h, x * sx - 0.5, y * sy - 0.5);
+            });
+            if w <= 1 || h <= 1 {
+                break;
+            }
+        }
+        let scale = self.intensity / self.levels.max(1) as f64;
+        glow.iter().map(|&g| g * scale).collect()
+    }
+}
+
+//星形の回折グレア(Kawase 2003) 明るい部分を放射状の筋に引き伸ばす
+#[derive(Debug, Clone, Copy)]
+pub struct Glare {
+    pub threshold: f64,
+    pub intensity: f64,
+    //筋の本数
+    pub streaks: usize,
+    //筋の向きの回転(度)
+    pub angle: f64,
+    //1画素ごとの減衰 1に近いほど長い
+    pub decay: f64,
+}
+
+impl Glare {
+    //一方向の筋 パスごとに4倍の間隔で4点を重ねると指数的に長く伸びる
+    fn streak(&self, bright: &[Color], width: usize, height: usize, dir: (f64, f64)) -> Vec<Color> {
+        let mut src = bright.to_vec();
+        for pass in 0..3 {
+            let b = 4f64.powi(pass);
+            src = (0..width * height)
+                .into_par_iter()
+                .map(|p| {
+                    let (x, y) = ((p % width) as f64, (p / width) as f64);
+                    (0..4).fold(Color::zero(), |acc, s| {
+                        let d = b * s as f64;
+                        let w = self.decay.powf(d);
+                        acc + bilinear(&src, width, height, x + dir.0 * d, y + dir.1 * d) * w
+                    })
+                })
+                .collect();
+        }
+        src
+    }
+
+    //足し込む筋だけを返す
+    pub fn glow(&self, image: &[Color], width: usize, height: usize) -> Vec<Color> {
+        let bright = bright_pass(image, self.threshold);
+        let streaks = self.streaks.max(1);
+        //4点の重みの合計で割って、筋一本あたりの明るさを元の明るさに揃える
+        let norm = (0..3).fold(1.0, |acc, pass| {
+            let b = 4f64.powi(pass);
+            acc * (0..4).map(|s| self.decay.powf(b * s as f64)).sum::<f64>()
+        });
+        let scale = self.intensity / (norm * streaks as f64);
+        let mut glow = vec![Color::zero(); width * height];
+        for i in 0..streaks {
+            let theta = self.angle.to_radians() + 2.0 * PI * i as f64 / streaks as f64;
+            let streak = self.streak(&bright, width, height, (theta.cos(), theta.sin()));
+            for (g, s) in glow.iter_mut().zip(&streak) {
+                *g = *g + *s * scale;
+            }
+        }
+        glow
+    }
+}
+
+//色収差 赤は外側、青は内側にずらして画面の端ほど色がにじむ
+fn chromatic_aberration(image: &mut [Color], width: usize, height: usize, strength: f64) {
+    let src = image.to_vec();
+    let (cx, cy) = ((width as f64 - 1.0) / 2.0, (height as f64 - 1.0) / 2.0);
+    image.par_iter_mut().enumerate().for_each(|(p, c)| {
+        let (dx, dy) = ((p % width) as f64 - cx, (p / width) as f64 - cy);
+        let at = |k: f64| bilinear(&src, width, height, cx + dx * (1.0 - k), cy + dy * (1.0 - k));
+        *c = Color::new(at(strength).x, c.y, at(-strength).z);
+    });
+}
+
+//周辺減光 画面の隅でstrengthだけ暗くなる
+fn vignette(image: &mut [Color], width: usize, height: usize, strength: f64) {
+    let (cx, cy) = (width as f64 / 2.0, height as f64 / 2.0);
+    let r2_max = cx * cx + cy * cy;
+    image.par_iter_mut().enumerate().for_each(|(p, c)| {
+        let (dx, dy) = ((p % width) as f64 + 0.5 - cx, (p / width) as f64 + 0.5 - cy);
+        *c = *c * (1.0 - strength * (dx * dx + dy * dy) / r2_max).max(0.0);
+    });
+}
+
+//使う効果とその設定 Noneや0なら何もしない
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PostEffects {
+    pub bloom: Option<Bloom>,
+    pub glare: Option<Glare>,
+    //画面の隅でのずれの割合
+    pub chromatic_aberration: f64,
+    pub vignette: f64,
+}
+
+impl PostEffects {
+    //レンズを通る順に 色収差 → にじみ → 周辺減光
+    pub fn apply(&self, image: &mut [Color], width: usize, height: usize) {
+        if self.chromatic_aberration != 0.0 {
+            chromatic_aberration(image, width, height, self.chromatic_aberration);
+        }
+        //ブルームとグレアはどちらも色収差の後の同じ画像から作って足す
+        let glows: Vec<Vec<Color>> = [
+            self.bloom.map(|b| b.glow(image, width, height)),
+            self.glare.map(|g| g.glow(image, width, height)),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        for glow in &glows {
+            for (c, g) in image.iter_mut().zip(glow) {
+                *c = *c + *g;
+            }
+        }
+        if self.vignette != 0.0 {
+            vignette(image, width, height, self.vignette);
+        }
+    }
+}
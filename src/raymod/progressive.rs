@@ -0,0 +1,84 @@
+use std::time::{Duration, Instant};
+
+//プログレッシブ描画で途中経過の画像を書き出す間隔
+//秒数とパス数のどちらかに達したら書く どちらも指定がなければ10秒ごと
+#[derive(Debug)]
+pub struct Snapshots {
+    interval: Option<Duration>,
+    passes: Option<usize>,
+    last_time: Instant,
+    last_pass: usize,
+}
+
+impl Snapshots {
+    pub fn new(seconds: Option<f64>, passes: Option<usize>) -> Self {
+        let seconds = if seconds.is_none() && passes.is_none() { Some(10.0) } else { seconds };
+        Self {
+            interval: seconds.map(Duration::from_secs_f64),
+            passes: passes.map(|n| n.max(1)),
+            last_time: Instant::now(),
+            last_pass: 0,
+        }
+    }
+
+    //passはこれまでに終えたパスの数 書くべきなら時刻とパス数を記録してtrue
+    pub fn due(&mut self, pass: usize) -> bool {
+        let by_time = self.interval.is_some_and(|d| self.last_time.elapsed() >= d);
+        let by_pass = self.passes.is_some_and(|n| pass - self.last_pass >= n);
+        if by_time || by_pass {
+            self.last_time = Instant::now();
+            self.last_pass = pass;
+        }
+        by_time || by_pass
+    }
+}
+
+//-sの数の代わりに、時間か画像全体の誤差の目安でパスを打ち切る
+#[derive(Debug)]
+pub struct RenderBudget {
+    time_limit: Option<Duration>,
+    target_noise: Option<f64>,
+    //プログラムを始めた時刻 シーンの準備の時間も含めて数える
+    start: Instant,
+    pass_start: Instant,
+    //一番長くかかったパス 次のパスが時間内に収まるかの見積もりに使う
+    longest_pass: Duration,
+}
+
+impl RenderBudget {
+    pub fn new(start: Instant, time_limit: Option<f64>, target_noise: Option<f64>) -> Self {
+        Self {
+            time_limit: time_limit.map(Duration::from_secs_f64),
+            target_noise,
+            start,
+            pass_start: Instant::now(),
+            longest_pass: Duration::ZERO,
+        }
+    }
+
+    //どちらかが指定されていればパスの数は予算で決まる
+    pub fn is_set(&self) -> bool {
+        self.time_limit.is_some() || self.target_noise.is_some()
+    }
+
+    pub fn begin_pass(&mut self) {
+        self.pass_start = Instant::now();
+    }
+
+    //パスを終えたところで呼ぶ noiseは画像全体の誤差の見積もり 止めるならその理由を返す
+    pub fn end_pass(&mut self, noise: f64) -> Option<String> {
+        self.longest_pass = self.longest_pass.max(self.pass_start.elapsed());
+        if let Some(target) = self.target_noise
+            && noise <= target
+        {
+            return Some(format!("noise {:.4} reached target {}", noise, target));
+        }
+        let elapsed = self.start.elapsed();
+        match self.time_limit {
+            Some(limit) if elapsed + self.longest_pass > limit => {
+                Some(format!("time limit {:.1}s (elapsed {:.1}s)", limit.as_secs_f64(), elapsed.as_secs_f64()))
+            }
+            _ => None,
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use crate::raymod::*;
+use std::sync::Arc;
 
 
 /// A quaternion
+#[derive(Clone, Copy)]
 pub struct Quat(Vec3, f64);
 
  #[allow(dead_code)]
@@ -72,7 +74,7 @@ impl Quat {
     }
 
     /// Returns as array
-    pub fn to_array(&self) -> [f64; 4] {
+    pub fn to_array(self) -> [f64; 4] {
         [self.0.x, self.0.y, self.0.z, self.1]
     }
 
@@ -83,7 +85,7 @@ impl Quat {
         let x = (w1 * x2 + y1 * z2) - (z1 * y2);
         let y = (w1 * y2 + z1 * x2) - (x1 * z2);
         let z = (w1 * z2 + x1 * y2) - (y1 * x2);
-        let w = (x1 * x2 + y1 * y2) - (z1 * z2);
+        let w = (x1 * x2 + y1 * y2) + (z1 * z2);
         Vec3::new(
             ((w * x1 + x * w1) - y * z1) + z * y1,
             ((w * y1 + y * w1) - z * x1) + x * z1,
@@ -110,7 +112,7 @@ impl std::ops::Mul<Quat> for Quat {
         let [x2, y2, z2, w2] = rhs.to_array();
         Quat::new(
             w1 * x2 + x1 * w2 + y1 * z2 - z1 * y2,
-            w1 * y2 + y2 * w2 + z1 * x2 - x1 * z2,
+            w1 * y2 + y1 * w2 + z1 * x2 - x1 * z2,
             w1 * z2 + z1 * w2 + x1 * y2 - y1 * x2,
             w1 * w2 - x1 * x2 - y1 * y2 - z1 * z2,
         )
@@ -145,6 +147,12 @@ impl Shape for Translate {
             None
         }
     }
+    fn collect_lights(&self, xf: &Transform, lights: &mut Vec<Box<dyn Light>>) {
+        self.shape.collect_lights(&xf.translated(self.offset), lights);
+    }
+    fn collect_materials(&self, materials: &mut Vec<Arc<dyn Material>>) {
+        self.shape.collect_materials(materials);
+    }
 }
 pub struct Rotate {
     pub shape: Box<dyn Shape>,
@@ -160,7 +168,13 @@ impl Shape for Rotate {
         let revq = self.quat.conj();
         let rotated_ray = Ray::new(revq.rotate(ray.o), revq.rotate(ray.d));
         if let Some(hit) = self.shape.hit(&rotated_ray, t0, t1) {
-            Some(HitInfo { p: self.quat.rotate(hit.p), n: self.quat.rotate(hit.n), ..hit })
+            Some(HitInfo {
+                p: self.quat.rotate(hit.p),
+                n: self.quat.rotate(hit.n),
+                dpdu: self.quat.rotate(hit.dpdu),
+                dpdv: self.quat.rotate(hit.dpdv),
+                ..hit
+            })
         } else {
             None
         }
@@ -190,4 +204,48 @@ impl Shape for Rotate {
             None
         }
     }
+    fn collect_lights(&self, xf: &Transform, lights: &mut Vec<Box<dyn Light>>) {
+        self.shape.collect_lights(&xf.rotated(self.quat), lights);
+    }
+    fn collect_materials(&self, materials: &mut Vec<Arc<dyn Material>>) {
+        self.shape.collect_materials(materials);
+    }
+}
+
+//子の座標系からワールドへの剛体変換(回転してから平行移動) 面光源の収集に使う
+#[derive(Clone, Copy)]
+pub struct Transform {
+    pub rot: Quat,
+    pub offset: Vec3,
+    //FlipFaceの中なら法線を反転する
+    pub flip: bool,
+}
+
+impl Transform {
+    pub const fn identity() -> Self {
+        Self { rot: Quat::unit(), offset: Vec3 { x: 0.0, y: 0.0, z: 0.0 }, flip: false }
+    }
+    pub fn point(&self, p: Vec3) -> Vec3 {
+        self.rot.rotate(p) + self.offset
+    }
+    pub fn vector(&self, v: Vec3) -> Vec3 {
+        self.rot.rotate(v)
+    }
+    pub fn normal(&self, n: Vec3) -> Vec3 {
+        let n = self.rot.rotate(n);
+        if self.flip { -n } else { n }
+    }
+    //ワールドの点を子の座標系へ戻す
+    pub fn inverse_point(&self, p: Vec3) -> Vec3 {
+        self.rot.conj().rotate(p - self.offset)
+    }
+    pub fn translated(&self, offset: Vec3) -> Self {
+        Self { offset: self.point(offset), ..*self }
+    }
+    pub fn rotated(&self, quat: Quat) -> Self {
+        Self { rot: self.rot * quat, ..*self }
+    }
+    pub fn flipped(&self) -> Self {
+        Self { flip: !self.flip, ..*self }
+    }
 }
@@ -1,23 +1,34 @@
 ﻿use crate::raymod::*;
 
 use std::f64::consts::*;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Ray {
     pub o: Vec3,
     pub d: Vec3,
+    //レイコーン 始点での幅と距離あたりの広がり角
+    pub width: f64,
+    pub spread: f64,
 }
 
 impl Ray {
     pub fn new(o: Vec3, d: Vec3) -> Ray {
-        Ray { o, d }
+        Ray { o, d, width: 0.0, spread: 0.0 }
+    }
+    pub fn with_cone(self, width: f64, spread: f64) -> Ray {
+        Ray { width, spread, ..self }
+    }
+    //距離tでのレイコーンの幅
+    pub fn cone_width(&self, t: f64) -> f64 {
+        self.width + self.spread * t * self.d.length().sqrt()
     }
     pub fn at(&self, t: f64) -> Vec3 {
         self.o + self.d * t
     }
 }
 
+#[derive(Clone)]
 pub struct HitInfo {
     pub t: f64,
     pub p: Vec3,
@@ -25,11 +36,25 @@ pub struct HitInfo {
     pub m: Arc<dyn Material>,
     pub u: f64,
     pub v: f64,
+    //接線空間 uv方向の位置の微分
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    //テクスチャフィルタ用 交点でのピクセルの広がり(ワールド座標)
+    pub footprint: f64,
+    //面光源として集められた形状なら、その光源の番号
+    pub light: Option<usize>,
 }
 
 impl HitInfo {
     pub fn new(t: f64, p: Vec3, n: Vec3, m: Arc<dyn Material>, u: f64, v: f64) -> Self {
-        Self { t, p, n, m, u, v }
+        let (dpdu, dpdv) = n.orthonormal_basis();
+        Self { t, p, n, m, u, v, dpdu, dpdv, footprint: 0.0, light: None }
+    }
+    pub fn with_tangent(self, dpdu: Vec3, dpdv: Vec3) -> Self {
+        Self { dpdu, dpdv, ..self }
+    }
+    pub fn with_light(self, light: Option<usize>) -> Self {
+        Self { light, ..self }
     }
 }
 
@@ -38,6 +63,11 @@ impl HitInfo {
 pub trait Shape: Sync {
     fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo>;
     fn bounding_box(&self) -> Option<AABB>;
+    //DiffuseLightを持つ形状を面光源として集める xfは親からワールドへの変換
+    //集めた形状は光源の番号を覚えておき、交点情報に載せる
+    fn collect_lights(&self, _xf: &Transform, _lights: &mut Vec<Box<dyn Light>>) {}
+    //含まれるマテリアルをシーンを組んだ順に並べる 同じものが何度入ってもよい
+    fn collect_materials(&self, _materials: &mut Vec<Arc<dyn Material>>) {}
 }
 
 //法線逆転用
@@ -60,13 +90,71 @@ impl Shape for FlipFace {
     fn bounding_box(&self) -> Option<AABB> {
         self.shape.bounding_box() 
     }
+    fn collect_lights(&self, xf: &Transform, lights: &mut Vec<Box<dyn Light>>) {
+        self.shape.collect_lights(&xf.flipped(), lights);
+    }
+    fn collect_materials(&self, materials: &mut Vec<Arc<dyn Material>>) {
+        self.shape.collect_materials(materials);
+    }
 }
 
 
+//不透明度マスクの取り出し方
+pub enum AlphaChannel {
+    Luminance,
+    Alpha,
+}
+
+//切り抜きマスク 透明部分はレイが素通りする。半透明は確率的に通過させる
+pub struct AlphaMask {
+    pub shape: Box<dyn Shape>,
+    pub mask: Box<dyn Texture>,
+    pub channel: AlphaChannel,
+}
+impl AlphaMask {
+    //テクスチャの輝度を不透明度とする
+    pub fn new(shape: Box<dyn Shape>, mask: Box<dyn Texture>) -> Self {
+        Self { shape, mask, channel: AlphaChannel::Luminance }
+    }
+    //画像のアルファチャンネルを不透明度とする
+    pub fn from_alpha(shape: Box<dyn Shape>, mask: Box<dyn Texture>) -> Self {
+        Self { shape, mask, channel: AlphaChannel::Alpha }
+    }
+    fn opacity(&self, hit: &HitInfo) -> f64 {
+        match self.channel {
+            AlphaChannel::Luminance => self.mask.value_at(hit).luminance(),
+            AlphaChannel::Alpha => self.mask.alpha(hit.u, hit.v, hit.p),
+        }
+    }
+}
+impl Shape for AlphaMask {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        const MAX_LAYERS: usize = 64;
+        let mut t_min = t0;
+        for _ in 0..MAX_LAYERS {
+            let hit = self.shape.hit(ray, t_min, t1)?;
+            let alpha = self.opacity(&hit);
+            if alpha >= 1.0 || (alpha > 0.0 && random() < alpha) {
+                return Some(hit);
+            }
+            t_min = hit.t + EPS10;
+        }
+        None
+    }
+    fn bounding_box(&self) -> Option<AABB> {
+        self.shape.bounding_box()
+    }
+    //切り抜かれた部分まで光源としてサンプリングしてしまうので集めない
+    fn collect_materials(&self, materials: &mut Vec<Arc<dyn Material>>) {
+        self.shape.collect_materials(materials);
+    }
+}
+
 pub struct Sphere {
     pub center: Vec3,
     pub radius: f64,
     pub material: Arc<dyn Material>,
+    light: OnceLock<usize>,
 }
 
 impl Sphere {
@@ -75,13 +163,34 @@ impl Sphere {
             center,
             radius,
             material,
+            light: OnceLock::new(),
         }
     }
-    fn uv(p: Vec3) -> (f64, f64) {
+    pub(crate) fn uv(p: Vec3) -> (f64, f64) {
         let phi = p.z.atan2(p.x);
         let theta = p.y.asin();
         (1.0 - (phi + PI) / (2.0 * PI), (theta + PI / 2.0) / PI)
     }
+    //uvの定義 u=1-(phi+PI)/2PI, v=(theta+PI/2)/PI を微分したもの
+    pub(crate) fn tangent(&self, n: Vec3) -> (Vec3, Vec3) {
+        let rho = (n.x * n.x + n.z * n.z).sqrt();
+        if rho < EPS {
+            return n.orthonormal_basis();
+        }
+        let r = self.radius;
+        let dpdu = 2.0 * PI * r * Vec3::new(n.z, 0.0, -n.x);
+        let dpdv = PI * r * Vec3::new(-n.y * n.x / rho, rho, -n.y * n.z / rho);
+        (dpdu, dpdv)
+    }
+    fn hit_info(&self, r: &Ray, t: f64) -> HitInfo {
+        let p = r.at(t);
+        let n = (p - self.center) / self.radius;
+        let (u, v) = Self::uv(n);
+        let (dpdu, dpdv) = self.tangent(n);
+        HitInfo::new(t, p, n, Arc::clone(&self.material), u, v)
+            .with_tangent(dpdu, dpdv)
+            .with_light(self.light.get().copied())
+    }
 }
 
 impl Shape for Sphere {
@@ -96,17 +205,11 @@ impl Shape for Sphere {
             let root = d.sqrt();
             let temp = (-b - root) / (2.0 * a);
             if temp < t1 && temp > t0 {
-                let p = r.at(temp);
-                let n = (p - self.center) / self.radius;
-                let (u, v) = Self::uv(n);
-                return Some(HitInfo::new(temp, p, n, Arc::clone(&self.material), u, v));
+                return Some(self.hit_info(r, temp));
             }
             let temp = (-b + root) / (2.0 * a);
             if temp < t1 && temp > t0 {
-                let p = r.at(temp);
-                let n = (p - self.center) / self.radius;
-                let (u, v) = Self::uv(n);
-                return Some(HitInfo::new(temp, p, n, Arc::clone(&self.material), u, v));
+                return Some(self.hit_info(r, temp));
             }
         }
         None
@@ -117,6 +220,16 @@ impl Shape for Sphere {
         let max = self.center + radius;
         Some(AABB { min, max })
     }
+    fn collect_lights(&self, xf: &Transform, lights: &mut Vec<Box<dyn Light>>) {
+        if self.material.is_emissive() {
+            let _ = self.light.set(lights.len());
+            let sphere = Sphere::new(self.center, self.radius, Arc::clone(&self.material));
+            lights.push(Box::new(AreaLight::new(AreaShape::Sphere(sphere), Arc::clone(&self.material), *xf)));
+        }
+    }
+    fn collect_materials(&self, materials: &mut Vec<Arc<dyn Material>>) {
+        materials.push(Arc::clone(&self.material));
+    }
 }
 
 pub enum RectAxisType {
@@ -132,6 +245,7 @@ pub struct Rect {
     pub k: f64,
     pub axis: RectAxisType,
     pub material: Arc<dyn Material>,
+    light: OnceLock<usize>,
 }
 
 impl Rect {
@@ -140,7 +254,7 @@ impl Rect {
     ) -> Self {
     let x0 = _x0.min(_x1);let x1 = _x1.max(_x0);
     let y0 = _y0.min(_y1);let y1 = _y1.max(_y0);
-        Self {x0,x1,y0, y1,k,axis, material,}
+        Self {x0,x1,y0, y1,k,axis, material, light: OnceLock::new(),}
     }
 }
 
@@ -149,17 +263,20 @@ impl Shape for Rect {
         let mut origin = ray.o;
         let mut direction = ray.d;
         let mut axis = Vec3::zaxis();
+        let (mut tu, mut tv) = (Vec3::xaxis(), Vec3::yaxis());
         match self.axis {
             RectAxisType::XY => {}
             RectAxisType::XZ => {
                 origin = Vec3::new(origin.x, origin.z, origin.y);
                 direction = Vec3::new(direction.x, direction.z, direction.y);
                 axis = Vec3::yaxis();
+                tv = Vec3::zaxis();
             }
             RectAxisType::YZ => {
                 origin = Vec3::new(origin.y, origin.z, origin.x);
                 direction = Vec3::new(direction.y, direction.z, direction.x);
                 axis = Vec3::xaxis();
+                (tu, tv) = (Vec3::yaxis(), Vec3::zaxis());
             }
         }
         let t = (self.k - origin.z) / direction.z;
@@ -179,7 +296,8 @@ impl Shape for Rect {
             Arc::clone(&self.material),
             (x - self.x0) / (self.x1 - self.x0),
             (y - self.y0) / (self.y1 - self.y0),
-        ))
+        ).with_tangent(tu * (self.x1 - self.x0), tv * (self.y1 - self.y0))
+            .with_light(self.light.get().copied()))
     }
 
     fn bounding_box(&self) -> Option<AABB> {
@@ -201,6 +319,37 @@ impl Shape for Rect {
         }
         Some(AABB { min, max })
     }
+    fn collect_lights(&self, xf: &Transform, lights: &mut Vec<Box<dyn Light>>) {
+        if !self.material.is_emissive() {
+            return;
+        }
+        let (dx, dy) = (self.x1 - self.x0, self.y1 - self.y0);
+        let quad = match self.axis {
+            RectAxisType::XY => AreaShape::Quad {
+                origin: Vec3::new(self.x0, self.y0, self.k),
+                e1: Vec3::new(dx, 0.0, 0.0),
+                e2: Vec3::new(0.0, dy, 0.0),
+                n: Vec3::zaxis(),
+            },
+            RectAxisType::XZ => AreaShape::Quad {
+                origin: Vec3::new(self.x0, self.k, self.y0),
+                e1: Vec3::new(dx, 0.0, 0.0),
+                e2: Vec3::new(0.0, 0.0, dy),
+                n: Vec3::yaxis(),
+            },
+            RectAxisType::YZ => AreaShape::Quad {
+                origin: Vec3::new(self.k, self.x0, self.y0),
+                e1: Vec3::new(0.0, dx, 0.0),
+                e2: Vec3::new(0.0, 0.0, dy),
+                n: Vec3::xaxis(),
+            },
+        };
+        let _ = self.light.set(lights.len());
+        lights.push(Box::new(AreaLight::new(quad, Arc::clone(&self.material), *xf)));
+    }
+    fn collect_materials(&self, materials: &mut Vec<Arc<dyn Material>>) {
+        materials.push(Arc::clone(&self.material));
+    }
 }
 
 pub struct RectAngle {
@@ -245,44 +394,57 @@ impl Shape for RectAngle {
         let max=self.p_max;
         Some(AABB { min, max })
     }
+    fn collect_lights(&self, xf: &Transform, lights: &mut Vec<Box<dyn Light>>) {
+        self.shapes.collect_lights(xf, lights);
+    }
+    fn collect_materials(&self, materials: &mut Vec<Arc<dyn Material>>) {
+        self.shapes.collect_materials(materials);
+    }
 }
 
 pub struct ShapeList {
     pub objects: Vec<Box<dyn Shape>>,
+    //形状を持たない光源(点光源など)
+    pub lights: Vec<Box<dyn Light>>,
 }
 
 impl ShapeList {
     pub fn new() -> Self {
         Self {
             objects: Vec::new(),
+            lights: Vec::new(),
         }
     }
     pub fn push(&mut self, object: Box<dyn Shape>) {
         self.objects.push(object);
     }
-}
-
-impl Shape for ShapeList {
-    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
-        let mut hit_info: Option<HitInfo> = None;
+    pub fn push_light(&mut self, light: Box<dyn Light>) {
+        self.lights.push(light);
+    }
+    //一番近い交点と、それを持つobjectsの番号
+    pub fn hit_object(&self, ray: &Ray, t0: f64, t1: f64) -> Option<(usize, HitInfo)> {
+        let mut hit_info: Option<(usize, HitInfo)> = None;
         let mut closest_so_far = t1;
-        for object in &self.objects {
+        for (i, object) in self.objects.iter().enumerate() {
             if let Some(info) = object.hit(ray, t0, closest_so_far) {
                 closest_so_far = info.t;
-                hit_info = Some(info);
+                hit_info = Some((i, info));
             }
         }
         hit_info
     }
+}
+
+impl Shape for ShapeList {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        self.hit_object(ray, t0, t1).map(|(_, info)| info)
+    }
     fn bounding_box(&self) -> Option<AABB> {
         match &self.objects.first() {
             Some(first) => {
                 match first.bounding_box() {
                     Some(bbox) => self.objects.iter().skip(1).try_fold(bbox, |acc, shape| {
-                        match shape.bounding_box() {
-                            Some(bbox) => Some(surrounding_box(&acc, &bbox)),
-                            _ => None,
-                        }
+                        shape.bounding_box().map(|bbox| surrounding_box(&acc, &bbox))
                     }),
                     _ => None,
                 }
@@ -290,4 +452,14 @@ impl Shape for ShapeList {
             _ => None,
         }
     }
+    fn collect_lights(&self, xf: &Transform, lights: &mut Vec<Box<dyn Light>>) {
+        for object in &self.objects {
+            object.collect_lights(xf, lights);
+        }
+    }
+    fn collect_materials(&self, materials: &mut Vec<Arc<dyn Material>>) {
+        for object in &self.objects {
+            object.collect_materials(materials);
+        }
+    }
 }
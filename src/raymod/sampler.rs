@@ -0,0 +1,466 @@
+use std::cell::{Cell, RefCell};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+//画素ごとのサンプル列 index番目のサンプルのdim次元目の値を返す
+//カメラはフィルムに0,1次元、レンズに2,3次元を使い、反射ごとにBOUNCE_DIMSずつ進める
+pub trait Sampler: Send + Sync {
+    fn get_1d(&self, pixel: (usize, usize), index: usize, dim: usize) -> f64;
+    fn get_2d(&self, pixel: (usize, usize), index: usize, dim: usize) -> (f64, f64) {
+        (self.get_1d(pixel, index, dim), self.get_1d(pixel, index, dim + 1))
+    }
+}
+
+const CAMERA_DIMS: usize = 4;
+const BOUNCE_DIMS: usize = 16;
+
+//64bitの値をよく混ぜる(splitmix64の後半)
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e3779b97f4a7c15, |h, &v| mix(h ^ v.wrapping_add(0x9e3779b97f4a7c15)))
+}
+
+//シードなどに使う、実行環境によらないハッシュ
+pub fn stable_hash(values: &[u64]) -> u64 {
+    hash(values)
+}
+
+fn to_unit(h: u64) -> f64 {
+    (h >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+}
+
+fn pixel_hash(seed: u64, pixel: (usize, usize), dim: usize) -> u64 {
+    hash(&[seed, pixel.0 as u64, pixel.1 as u64, dim as u64])
+}
+
+//毎回独立な一様乱数 画素・番号・次元のハッシュから作るので再現できる
+pub struct IndependentSampler {
+    seed: u64,
+}
+
+impl Sampler for IndependentSampler {
+    fn get_1d(&self, pixel: (usize, usize), index: usize, dim: usize) -> f64 {
+        to_unit(hash(&[self.seed, pixel.0 as u64, pixel.1 as u64, index as u64, dim as u64]))
+    }
+}
+
+//Kensler 2013 "Correlated Multi-Jittered Sampling" の置換と乱数
+fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+
+fn randfloat(mut i: u32, p: u32) -> f64 {
+    i ^= p;
+    i ^= i >> 17;
+    i ^= i >> 10;
+    i = i.wrapping_mul(0xb36534e5);
+    i ^= i >> 12;
+    i ^= i >> 21;
+    i = i.wrapping_mul(0x93fc4795);
+    i ^= 0xdf6e307f;
+    i ^= i >> 17;
+    i = i.wrapping_mul(1 | p >> 18);
+    i as f64 / 4294967808.0
+}
+
+//層化サンプリング 1次元は画素あたりのサンプル数で等分、2次元は相関多重ジッタ
+pub struct StratifiedSampler {
+    seed: u64,
+    spp: usize,
+}
+
+impl StratifiedSampler {
+    //サンプル数を超えた分は次の組として別の置換を使う
+    fn pattern(&self, pixel: (usize, usize), index: usize, dim: usize) -> (u32, u32, u32) {
+        let n = self.spp.max(1);
+        let round = (index / n) as u64;
+        let p = hash(&[pixel_hash(self.seed, pixel, dim), round]) as u32;
+        (permute((index % n) as u32, n as u32, p.wrapping_mul(0x51633e2d)), n as u32, p)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn get_1d(&self, pixel: (usize, usize), index: usize, dim: usize) -> f64 {
+        let (s, n, p) = self.pattern(pixel, index, dim);
+        (s as f64 + randfloat(s, p.wrapping_mul(0x68bc21eb))) / n as f64
+    }
+    fn get_2d(&self, pixel: (usize, usize), index: usize, dim: usize) -> (f64, f64) {
+        let (s, n, p) = self.pattern(pixel, index, dim);
+        let m = ((n as f64).sqrt() as u32).max(1);
+        let rows = n.div_ceil(m);
+        let sx = permute(s % m, m, p.wrapping_mul(0xa511e9b3));
+        let sy = permute(s / m, rows, p.wrapping_mul(0x63d83595));
+        let jx = randfloat(s, p.wrapping_mul(0xa399d265));
+        let jy = randfloat(s, p.wrapping_mul(0x711ad6a5));
+        let x = ((s % m) as f64 + (sy as f64 + jx) / rows as f64) / m as f64;
+        let y = ((s / m) as f64 + (sx as f64 + jy) / m as f64) / rows as f64;
+        (x.min(1.0 - f64::EPSILON), y.min(1.0 - f64::EPSILON))
+    }
+}
+
+fn primes() -> &'static [u64] {
+    static PRIMES: OnceLock<Vec<u64>> = OnceLock::new();
+    PRIMES.get_or_init(|| {
+        let mut primes = Vec::new();
+        let mut n = 2;
+        while primes.len() < HaltonSampler::MAX_DIMS {
+            if primes.iter().all(|p| n % p != 0) {
+                primes.push(n);
+            }
+            n += 1;
+        }
+        primes
+    })
+}
+
+//Halton列 次元ごとに素数の基数を使い、画素ごとに各桁をランダムに並べ替える
+//ずらすだけだと大きな基数の次元どうしが直線状に並んだままになる
+pub struct HaltonSampler {
+    seed: u64,
+}
+
+impl HaltonSampler {
+    const MAX_DIMS: usize = 256;
+}
+
+impl Sampler for HaltonSampler {
+    fn get_1d(&self, pixel: (usize, usize), index: usize, dim: usize) -> f64 {
+        if dim >= Self::MAX_DIMS {
+            return to_unit(hash(&[self.seed, pixel.0 as u64, pixel.1 as u64, index as u64, dim as u64]));
+        }
+        let base = primes()[dim];
+        let h = pixel_hash(self.seed, pixel, dim);
+        let inv = 1.0 / base as f64;
+        let mut i = index as u64;
+        let mut scale = inv;
+        let mut value = 0.0;
+        let mut k = 0;
+        //indexの桁が尽きた後も0の桁を並べ替えるので倍精度の桁まで続ける
+        while scale > 1e-15 {
+            let digit = i % base;
+            let digit = permute(digit as u32, base as u32, hash(&[h, k]) as u32);
+            value += digit as f64 * scale;
+            i /= base;
+            scale *= inv;
+            k += 1;
+        }
+        value.min(1.0 - f64::EPSILON)
+    }
+}
+
+//Sobol列の2次元目の生成行列 (x+1の原始多項式)
+fn sobol_matrix() -> &'static [u32; 32] {
+    static MATRIX: OnceLock<[u32; 32]> = OnceLock::new();
+    MATRIX.get_or_init(|| {
+        let mut v = [0u32; 32];
+        let mut m: u64 = 1;
+        for (k, v) in v.iter_mut().enumerate() {
+            *v = (m << (31 - k)) as u32;
+            m ^= m << 1;
+        }
+        v
+    })
+}
+
+fn sobol(index: u32, dim: usize) -> u32 {
+    if dim == 0 {
+        return index.reverse_bits();
+    }
+    let v = sobol_matrix();
+    (0..32).filter(|k| (index >> k) & 1 == 1).fold(0, |x, k| x ^ v[k])
+}
+
+//Owenスクランブル(Burley 2020 "Practical Hash-based Owen Scrambling")
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits();
+    x ^= x.wrapping_mul(0x3d20adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x05526c56);
+    x ^= x.wrapping_mul(0x53a22864);
+    x.reverse_bits()
+}
+
+//2次元ずつSobol列の最初の2次元を使い、組ごとに番号をシャッフルして並べる
+fn padded_sobol(seed: u64, index: usize, dim: usize) -> f64 {
+    let s = hash(&[seed, (dim / 2) as u64]);
+    let i = nested_uniform_scramble(index as u32, s as u32);
+    let x = nested_uniform_scramble(sobol(i, dim % 2), (s >> 32) as u32 ^ ((dim % 2) as u32 * 0x9e3779b9));
+    x as f64 / 4294967296.0
+}
+
+//Owenスクランブルした Sobol列 画素ごとに別のスクランブルを使う
+pub struct SobolSampler {
+    seed: u64,
+}
+
+impl Sampler for SobolSampler {
+    fn get_1d(&self, pixel: (usize, usize), index: usize, dim: usize) -> f64 {
+        padded_sobol(pixel_hash(self.seed, pixel, 0), index, dim)
+    }
+}
+
+//ブルーノイズのマスク(void-and-cluster, Ulichney 1993) 0..1の順位を並べたもの
+const MASK_SIZE: usize = 64;
+
+fn blue_noise_mask() -> &'static [f64] {
+    static MASK: OnceLock<Vec<f64>> = OnceLock::new();
+    MASK.get_or_init(|| {
+        const N: usize = MASK_SIZE * MASK_SIZE;
+        const SIGMA: f64 = 1.5;
+        //周期境界でのガウス関数の表
+        let kernel: Vec<f64> = (0..N)
+            .map(|i| {
+                let d = |a: usize| {
+                    let a = a as f64;
+                    a.min(MASK_SIZE as f64 - a)
+                };
+                let (dx, dy) = (d(i % MASK_SIZE), d(i / MASK_SIZE));
+                (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+            })
+            .collect();
+        let update = |energy: &mut [f64], p: usize, sign: f64| {
+            let (px, py) = (p % MASK_SIZE, p / MASK_SIZE);
+            for (q, e) in energy.iter_mut().enumerate() {
+                let dx = (q % MASK_SIZE + MASK_SIZE - px) % MASK_SIZE;
+                let dy = (q / MASK_SIZE + MASK_SIZE - py) % MASK_SIZE;
+                *e += sign * kernel[dy * MASK_SIZE + dx];
+            }
+        };
+        //点のある場所で一番混んでいるところ、ない場所で一番空いているところ
+        let tightest = |pattern: &[bool], energy: &[f64]| {
+            (0..N).filter(|&i| pattern[i]).max_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+        };
+        let largest_void = |pattern: &[bool], energy: &[f64]| {
+            (0..N).filter(|&i| !pattern[i]).min_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+        };
+
+        //初期配置 一割の点を置いて、混んだ点を空いた所へ動かし続ける
+        let mut pattern = vec![false; N];
+        let mut energy = vec![0.0; N];
+        let initial = N / 10;
+        let mut placed = 0;
+        let mut k = 0;
+        while placed < initial {
+            let p = (hash(&[0xb10e, k]) % N as u64) as usize;
+            k += 1;
+            if !pattern[p] {
+                pattern[p] = true;
+                update(&mut energy, p, 1.0);
+                placed += 1;
+            }
+        }
+        loop {
+            let c = tightest(&pattern, &energy);
+            pattern[c] = false;
+            update(&mut energy, c, -1.0);
+            let v = largest_void(&pattern, &energy);
+            pattern[v] = true;
+            update(&mut energy, v, 1.0);
+            if v == c {
+                break;
+            }
+        }
+
+        let mut rank = vec![0usize; N];
+        //初期配置の点は混んだところから順に抜いて順位を下から付ける
+        {
+            let mut pattern = pattern.clone();
+            let mut energy = energy.clone();
+            for r in (0..initial).rev() {
+                let c = tightest(&pattern, &energy);
+                pattern[c] = false;
+                update(&mut energy, c, -1.0);
+                rank[c] = r;
+            }
+        }
+        //残りは空いたところから順に埋める
+        for r in initial..N {
+            let v = largest_void(&pattern, &energy);
+            pattern[v] = true;
+            update(&mut energy, v, 1.0);
+            rank[v] = r;
+        }
+        rank.iter().map(|&r| (r as f64 + 0.5) / N as f64).collect()
+    })
+}
+
+//全画素で同じSobol列を使い、ブルーノイズのマスクでずらす(Cranley-Patterson回転)
+//少ないサンプル数で誤差が画面上で高周波のノイズになる
+pub struct BlueNoiseSampler {
+    seed: u64,
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn get_1d(&self, pixel: (usize, usize), index: usize, dim: usize) -> f64 {
+        let mask = blue_noise_mask();
+        //次元ごとにマスクの位置をずらして次元間の相関を避ける
+        let o = hash(&[self.seed, dim as u64]);
+        let x = (pixel.0 + (o as usize % MASK_SIZE)) % MASK_SIZE;
+        let y = (pixel.1 + ((o >> 32) as usize % MASK_SIZE)) % MASK_SIZE;
+        let v = padded_sobol(self.seed, index, dim) + mask[y * MASK_SIZE + x];
+        v - v.floor()
+    }
+}
+
+//サンプラーの種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+    BlueNoise,
+}
+
+impl FromStr for SamplerKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "independent" => Ok(SamplerKind::Independent),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            "bluenoise" => Ok(SamplerKind::BlueNoise),
+            _ => Err(format!("unknown sampler: {} (independent|stratified|halton|sobol|bluenoise)", s)),
+        }
+    }
+}
+
+impl SamplerKind {
+    //sppは画素あたりのサンプル数 層化の分割に使う
+    pub fn build(self, spp: usize, seed: u64) -> Arc<dyn Sampler> {
+        match self {
+            SamplerKind::Independent => Arc::new(IndependentSampler { seed }),
+            SamplerKind::Stratified => Arc::new(StratifiedSampler { seed, spp }),
+            SamplerKind::Halton => Arc::new(HaltonSampler { seed }),
+            SamplerKind::Sobol => Arc::new(SobolSampler { seed }),
+            SamplerKind::BlueNoise => {
+                blue_noise_mask();
+                Arc::new(BlueNoiseSampler { seed })
+            }
+        }
+    }
+}
+
+//--seedの値 シーンの生成や画素の外の乱数もすべてここから決まる
+static SEED: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_seed(seed: u64) {
+    SEED.store(seed, Ordering::Relaxed);
+    STREAM.with(|s| s.set(hash(&[seed])));
+}
+
+pub fn seed() -> u64 {
+    SEED.load(Ordering::Relaxed)
+}
+
+//描画中の画素のサンプル このスレッドのrandom()はここから次の次元を取る
+struct SampleContext {
+    sampler: Arc<dyn Sampler>,
+    pixel: (usize, usize),
+    index: usize,
+    dim: usize,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Option<SampleContext>> = const { RefCell::new(None) };
+    //画素の外で使う乱数列の状態(splitmix64)
+    static STREAM: Cell<u64> = Cell::new(hash(&[seed()]));
+}
+
+//keysで決まる乱数列をこのスレッドで始める
+//並列に処理する仕事ごとに番号を振って呼べば、どのスレッドが受け持っても同じ値になる
+pub fn start_stream(keys: &[u64]) {
+    let mut values = vec![seed()];
+    values.extend_from_slice(keys);
+    STREAM.with(|s| s.set(hash(&values)));
+}
+
+//このスレッドの乱数列の次の値
+pub fn next_stream() -> f64 {
+    STREAM.with(|s| {
+        let z = s.get().wrapping_add(0x9e3779b97f4a7c15);
+        s.set(z);
+        to_unit(mix(z))
+    })
+}
+
+//このスレッドでpixelのindex番目のサンプルを始める
+pub fn start_pixel_sample(sampler: &Arc<dyn Sampler>, pixel: (usize, usize), index: usize) {
+    CONTEXT.with(|c| {
+        *c.borrow_mut() = Some(SampleContext { sampler: sampler.clone(), pixel, index, dim: 0 });
+    });
+}
+
+//画素のサンプルを終える 以降のrandom()は普通の乱数に戻る
+pub fn end_pixel_sample() {
+    CONTEXT.with(|c| *c.borrow_mut() = None);
+}
+
+//depth回目の反射の次元から使う 前の反射で使いすぎていたらその続きから
+pub fn start_bounce(depth: usize) {
+    CONTEXT.with(|c| {
+        if let Some(ctx) = c.borrow_mut().as_mut() {
+            ctx.dim = ctx.dim.max(CAMERA_DIMS + depth * BOUNCE_DIMS);
+        }
+    });
+}
+
+//次の1次元 描画中でなければNone
+pub fn next_1d() -> Option<f64> {
+    CONTEXT.with(|c| {
+        c.borrow_mut().as_mut().map(|ctx| {
+            let v = ctx.sampler.get_1d(ctx.pixel, ctx.index, ctx.dim);
+            ctx.dim += 1;
+            v
+        })
+    })
+}
+
+//次の2次元 2次元の組が崩れないよう偶数の次元から始める
+pub fn next_2d() -> Option<(f64, f64)> {
+    CONTEXT.with(|c| {
+        c.borrow_mut().as_mut().map(|ctx| {
+            ctx.dim += ctx.dim % 2;
+            let v = ctx.sampler.get_2d(ctx.pixel, ctx.index, ctx.dim);
+            ctx.dim += 2;
+            v
+        })
+    })
+}
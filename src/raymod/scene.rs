@@ -1,7 +1,38 @@
 use crate::raymod::*;
 use std::sync::Arc;
+use std::f64::consts::PI;
+
+//シーンごとの描画設定の既定値 コマンドラインで指定した項目はそちらを使う
+#[derive(Debug, Clone, Copy)]
+pub struct SceneSettings {
+    pub max_depth: i64,
+    pub pipeline: ColorPipeline,
+}
+
+impl Default for SceneSettings {
+    fn default() -> Self {
+        Self { max_depth: 32, pipeline: ColorPipeline::default() }
+    }
+}
+
+impl SceneSettings {
+    pub fn with_max_depth(self, max_depth: i64) -> Self {
+        Self { max_depth, ..self }
+    }
+    pub fn with_pipeline(self, pipeline: ColorPipeline) -> Self {
+        Self { pipeline, ..self }
+    }
+}
+
+pub struct CameraSample {
+    pub lens: Vec3,
+    pub we: f64,
+    pub pdf: f64,
+    pub raster: (f64, f64),
+}
 
 //左上が原点なPNGフォーマット対応
+#[allow(dead_code)]
 pub struct Camera {
     pub origin: Vec3,
     pub upper_left_corner: Vec3,
@@ -11,6 +42,7 @@ pub struct Camera {
     pub u: Vec3,
     pub v: Vec3,
     pub w: Vec3,
+    pub viewport_height: f64,
 }
 
 impl Camera {
@@ -47,7 +79,79 @@ impl Camera {
             u,
             v,
             w,
+            viewport_height,
+        }
+    }
+
+    //1ピクセルあたりの視野角(ラジアン) レイコーンの広がりに使う
+    pub fn pixel_spread(&self, height: usize) -> f64 {
+        self.viewport_height / height as f64
+    }
+
+    pub fn focus_dist(&self) -> f64 {
+        (self.origin - self.upper_left_corner).dot(&self.w)
+    }
+
+    //焦点までの距離を1にしたときのフィルムの面積
+    fn film_area(&self) -> f64 {
+        let fd = self.focus_dist();
+        self.horizontal.length().sqrt() * self.vertical.length().sqrt() / (fd * fd)
+    }
+
+    //レンズの面積 ピンホールは1として扱う
+    pub fn lens_area(&self) -> f64 {
+        if self.lens_radius > 0.0 { PI * self.lens_radius * self.lens_radius } else { 1.0 }
+    }
+
+    //レンズ上の点oから方向dへ進むレイがフィルム上のどこに写るか(get_rayのs,t) 範囲外ならNone
+    pub fn raster(&self, o: Vec3, d: Vec3) -> Option<(f64, f64)> {
+        let cos = -d.dot(&self.w);
+        if cos <= 0.0 {
+            return None;
+        }
+        let q = o + d * ((o - self.upper_left_corner).dot(&self.w) / cos);
+        let rel = q - self.upper_left_corner;
+        let s = rel.dot(&self.horizontal) / self.horizontal.length();
+        let t = -rel.dot(&self.vertical) / self.vertical.length();
+        if (0.0..1.0).contains(&s) && (0.0..1.0).contains(&t) { Some((s, t)) } else { None }
+    }
+
+    //カメラの感度 We (Veach 1997) 正規化した向きdで、フィルムに写らなければ0
+    pub fn we(&self, o: Vec3, d: Vec3) -> f64 {
+        if self.raster(o, d).is_none() {
+            return 0.0;
+        }
+        let cos2 = self.w.dot(&d).powi(2);
+        1.0 / (self.film_area() * self.lens_area() * cos2 * cos2)
+    }
+
+    //カメラレイを選ぶ確率密度(面積あたり,立体角あたり)
+    pub fn pdf_we(&self, o: Vec3, d: Vec3) -> (f64, f64) {
+        if self.raster(o, d).is_none() {
+            return (0.0, 0.0);
+        }
+        let cos = -self.w.dot(&d);
+        (1.0 / self.lens_area(), 1.0 / (self.film_area() * cos * cos * cos))
+    }
+
+    //点pからレンズ上の点を選ぶ レンズ上の点、We、pから見た立体角あたりの確率密度、フィルム上の位置を返す
+    pub fn sample_wi(&self, p: Vec3) -> Option<CameraSample> {
+        let rd = Vec3::random_in_unit_disk() * self.lens_radius;
+        let lens = self.origin + rd.x * self.u + rd.y * self.v;
+        let d = lens - p;
+        let dist = d.length().sqrt();
+        if dist == 0.0 {
+            return None;
         }
+        let wi = d / dist;
+        let raster = self.raster(lens, -wi)?;
+        let cos = self.w.dot(&wi);
+        Some(CameraSample {
+            lens,
+            we: self.we(lens, -wi),
+            pdf: dist * dist / (cos.abs() * self.lens_area()),
+            raster,
+        })
     }
 
     pub fn get_ray(&self, s: f64, t: f64) -> Ray {
@@ -60,7 +164,7 @@ impl Camera {
     }
 }
 
-#[allow(dead_code)]
+#[allow(dead_code, clippy::vec_init_then_push)]
 impl ShapeList {
     pub fn simple_scene(&mut self) -> Camera {
         self.push(Box::new(Sphere::new(
@@ -95,7 +199,7 @@ impl ShapeList {
         let dist_to_focus = (lookfrom - lookat).length().sqrt();
         let aperture = 0.1;
 
-        return Camera::new(
+        Camera::new(
             lookfrom,
             lookat,
             vup,
@@ -103,16 +207,18 @@ impl ShapeList {
             WIDE_ASPECT,
             aperture,
             dist_to_focus,
-        );
+        )
     }
 
     pub fn texture_scene(&mut self) -> Camera {
         self.push(Box::new(Sphere::new(
             Vec3::new(0.6, 0.0, -1.0),
             0.5,
-            Arc::new(Lambertian::new(Box::new(ImageTexture::new(
-                "testimage.jpg",
-            )))),
+            Arc::new(Lambertian::new(Box::new(
+                ImageTexture::new("testimage.jpg")
+                    .with_srgb()
+                    .with_filter(TextureFilter::Trilinear),
+            ))),
         )));
         self.push(Box::new(Sphere::new(
             Vec3::new(-0.6, 0.0, -1.0),
@@ -139,7 +245,7 @@ impl ShapeList {
         let dist_to_focus = (lookfrom - lookat).length().sqrt();
         let aperture = 0.1;
 
-        return Camera::new(
+        Camera::new(
             lookfrom,
             lookat,
             vup,
@@ -147,7 +253,7 @@ impl ShapeList {
             WIDE_ASPECT,
             aperture,
             dist_to_focus,
-        );
+        )
     }
     pub fn emitte_scene(&mut self) -> Camera {
         self.push(Box::new(Sphere::new(
@@ -174,7 +280,7 @@ impl ShapeList {
         let dist_to_focus = (lookfrom - lookat).length().sqrt();
         let aperture = 0.1;
 
-        return Camera::new(
+        Camera::new(
             lookfrom,
             lookat,
             vup,
@@ -182,7 +288,7 @@ impl ShapeList {
             WIDE_ASPECT,
             aperture,
             dist_to_focus,
-        );
+        )
     }
     pub fn emitte_squre_scene(&mut self) -> Camera {
         self.push(Box::new(Rect::new(
@@ -215,7 +321,7 @@ impl ShapeList {
         let dist_to_focus = (lookfrom - lookat).length().sqrt();
         let aperture = 0.1;
 
-        return Camera::new(
+        Camera::new(
             lookfrom,
             lookat,
             vup,
@@ -223,7 +329,7 @@ impl ShapeList {
             WIDE_ASPECT,
             aperture,
             dist_to_focus,
-        );
+        )
     }
 
     pub fn cornellbox_scene(&mut self) -> Camera {
@@ -332,7 +438,7 @@ impl ShapeList {
         let dist_to_focus = (lookfrom - lookat).length().sqrt();
         let aperture = 0.1;
 
-        return Camera::new(
+        Camera::new(
             lookfrom,
             lookat,
             vup,
@@ -340,7 +446,7 @@ impl ShapeList {
             SQUARE_ASPECT,
             aperture,
             dist_to_focus,
-        );
+        )
     }
 
     pub fn cornell_scene(&mut self) -> Camera {
@@ -433,7 +539,294 @@ impl ShapeList {
         let dist_to_focus = (lookfrom - lookat).length().sqrt();
         let aperture = 0.1;
 
-        return Camera::new(
+        Camera::new(
+            lookfrom,
+            lookat,
+            vup,
+            40.0,
+            SQUARE_ASPECT,
+            aperture,
+            dist_to_focus,
+        )
+    }
+
+    pub fn bump_scene(&mut self) -> Camera {
+        self.push(Box::new(Sphere::new(
+            Vec3::new(0.6, 0.0, -1.0),
+            0.5,
+            Arc::new(BumpMap::new(
+                Arc::new(Lambertian::new(Box::new(ColorTexture::new(Vec3::new(
+                    0.1, 0.2, 0.5,
+                ))))),
+                Box::new(CheckerTexture::new(
+                    Box::new(ColorTexture::new(Vec3::new(0.0, 0.0, 0.0))),
+                    Box::new(ColorTexture::new(Vec3::new(1.0, 1.0, 1.0))),
+                    20.0,
+                )),
+                0.05,
+            )),
+        )));
+        self.push(Box::new(Sphere::new(
+            Vec3::new(-0.6, 0.0, -1.0),
+            0.5,
+            Arc::new(BumpMap::new(
+                Arc::new(Metal::new(
+                    Box::new(ColorTexture::new(Vec3::new(0.8, 0.8, 0.8))),
+                    0.1,
+                )),
+                Box::new(ImageTexture::new("testimage.jpg")),
+                0.005,
+            )),
+        )));
+        //接線空間ノーマルマップで鋲を並べた球
+        self.push(Box::new(Sphere::new(
+            Vec3::new(0.0, 0.0, -2.2),
+            0.5,
+            Arc::new(NormalMap::new(
+                Arc::new(Lambertian::new(Box::new(ColorTexture::new(Vec3::new(
+                    0.7, 0.3, 0.1,
+                ))))),
+                Box::new(
+                    ImageTexture::new("normalmap.png")
+                        .with_filter(TextureFilter::Bilinear)
+                        .with_wrap(WrapMode::Repeat)
+                        .with_uv_transform((4.0, 2.0), (0.0, 0.0), 0.0),
+                ),
+            )
+            .with_strength(1.5)),
+        )));
+        //床はポリゴン2枚
+        let positions = [
+            Vec3::new(-4.0, -0.5, -5.0),
+            Vec3::new(4.0, -0.5, -5.0),
+            Vec3::new(4.0, -0.5, 3.0),
+            Vec3::new(-4.0, -0.5, 3.0),
+        ];
+        let uvs = [(0.0, 1.0), (1.0, 1.0), (1.0, 0.0), (0.0, 0.0)];
+        self.push(Box::new(Mesh::new(
+            &positions,
+            Some(&uvs),
+            &[[0, 2, 1], [0, 3, 2]],
+            Arc::new(BumpMap::new(
+                Arc::new(Lambertian::new(Box::new(ColorTexture::new(Vec3::new(
+                    0.8, 0.8, 0.8,
+                ))))),
+                Box::new(
+                    ImageTexture::new("testimage.jpg")
+                        .with_filter(TextureFilter::Bilinear)
+                        .with_wrap(WrapMode::Mirror)
+                        .with_uv_transform((4.0, 4.0), (0.0, 0.0), 30.0),
+                ),
+                0.01,
+            )),
+        )));
+        // simple_scene用カメラ
+        let lookfrom = Vec3::new(0.0, 1.0, 4.0);
+        let lookat = Vec3::new(0.0, 0.0, -1.0);
+        let vup = Vec3::new(0.0, 1.0, 0.0);
+
+        let dist_to_focus = (lookfrom - lookat).length().sqrt();
+        let aperture = 0.1;
+
+        Camera::new(
+            lookfrom,
+            lookat,
+            vup,
+            20.0,
+            WIDE_ASPECT,
+            aperture,
+            dist_to_focus,
+        )
+    }
+
+    pub fn cutout_scene(&mut self) -> Camera {
+        //市松模様で穴をあけたフェンス
+        self.push(Box::new(AlphaMask::new(
+            Box::new(Rect::new(
+                -1.5, 1.5, -0.5, 1.0, -0.3, RectAxisType::XY,
+                Arc::new(Lambertian::new(Box::new(ColorTexture::new(Vec3::new(
+                    0.6, 0.4, 0.2,
+                ))))),
+            )),
+            Box::new(CheckerTexture::new(
+                Box::new(ColorTexture::new(Vec3::new(0.0, 0.0, 0.0))),
+                Box::new(ColorTexture::new(Vec3::new(1.0, 1.0, 1.0))),
+                15.0,
+            )),
+        )));
+        //半透明の球
+        self.push(Box::new(AlphaMask::new(
+            Box::new(Sphere::new(
+                Vec3::new(0.6, 0.0, -1.0),
+                0.5,
+                Arc::new(Lambertian::new(Box::new(ColorTexture::new(Vec3::new(
+                    0.1, 0.2, 0.5,
+                ))))),
+            )),
+            Box::new(ColorTexture::new(Vec3::new(0.5, 0.5, 0.5))),
+        )));
+        //PNGのアルファチャンネルで花の形に切り抜く板
+        self.push(Box::new(AlphaMask::from_alpha(
+            Box::new(Rect::new(
+                -1.3, -0.1, -0.5, 0.7, -1.5, RectAxisType::XY,
+                Arc::new(Lambertian::new(Box::new(
+                    ImageTexture::new("flower.png")
+                        .with_srgb()
+                        .with_filter(TextureFilter::Trilinear),
+                ))),
+            )),
+            Box::new(ImageTexture::new("flower.png")),
+        )));
+        self.push(Box::new(Sphere::new(
+            Vec3::new(0.0, -100.5, 0.0),
+            100.0,
+            Arc::new(Lambertian::new(Box::new(CheckerTexture::new(
+                Box::new(ColorTexture::new(Vec3::new(0.8, 0.8, 0.0))),
+                Box::new(ColorTexture::new(Vec3::new(0.8, 0.2, 0.0))),
+                10.0,
+            )))),
+        )));
+        // simple_scene用カメラ
+        let lookfrom = Vec3::new(0.0, 1.0, 4.0);
+        let lookat = Vec3::new(0.0, 0.0, -1.0);
+        let vup = Vec3::new(0.0, 1.0, 0.0);
+
+        let dist_to_focus = (lookfrom - lookat).length().sqrt();
+        let aperture = 0.1;
+
+        Camera::new(
+            lookfrom,
+            lookat,
+            vup,
+            20.0,
+            WIDE_ASPECT,
+            aperture,
+            dist_to_focus,
+        )
+    }
+
+    pub fn punctual_scene(&mut self) -> Camera {
+        self.push(Box::new(Sphere::new(
+            Vec3::new(0.6, 0.0, -1.0),
+            0.5,
+            Arc::new(Lambertian::new(Box::new(ColorTexture::new(Vec3::new(
+                0.1, 0.2, 0.5,
+            ))))),
+        )));
+        self.push(Box::new(Sphere::new(
+            Vec3::new(-0.6, 0.0, -1.0),
+            0.5,
+            Arc::new(Lambertian::new(Box::new(ColorTexture::new(Vec3::new(
+                0.8, 0.8, 0.8,
+            ))))),
+        )));
+        self.push(Box::new(Sphere::new(
+            Vec3::new(0.0, -100.5, 0.0),
+            100.0,
+            Arc::new(Lambertian::new(Box::new(ColorTexture::new(Vec3::new(
+                0.5, 0.5, 0.5,
+            ))))),
+        )));
+        //暖色の点光源(100W)
+        self.push_light(Box::new(PointLight::from_power(
+            Vec3::new(-1.5, 1.0, 0.0),
+            Color::new(100.0, 80.0, 60.0),
+        )));
+        //青い球を狙うスポットライト
+        self.push_light(Box::new(SpotLight::from_power(
+            Vec3::new(1.5, 2.0, 0.0),
+            Vec3::new(0.6, 0.0, -1.0),
+            Color::new(8.0, 8.0, 8.0),
+            10.0,
+            20.0,
+        )));
+        //弱い月明かり
+        self.push_light(Box::new(DirectionalLight::new(
+            Vec3::new(-1.0, -1.0, -0.5),
+            Color::new(0.2, 0.25, 0.4),
+        )));
+        // simple_scene用カメラ
+        let lookfrom = Vec3::new(0.0, 1.0, 4.0);
+        let lookat = Vec3::new(0.0, 0.0, -1.0);
+        let vup = Vec3::new(0.0, 1.0, 0.0);
+
+        let dist_to_focus = (lookfrom - lookat).length().sqrt();
+        let aperture = 0.1;
+
+        Camera::new(
+            lookfrom,
+            lookat,
+            vup,
+            20.0,
+            WIDE_ASPECT,
+            aperture,
+            dist_to_focus,
+        )
+    }
+
+    pub fn lamp_scene(&mut self) -> Camera {
+        let white = Color::new(0.73, 0.73, 0.73);
+        let red = Color::new(0.64, 0.05, 0.05);
+        let green = Color::new(0.12, 0.45, 0.15);
+
+        //天井の電球色ダウンライト 下向きだけ光る
+        let profile = match IesProfile::open("downlight.ies") {
+            Ok(ies) => EmissionProfile::Ies(ies),
+            Err(e) => {
+                eprintln!("failed to load downlight.ies: {}", e);
+                EmissionProfile::Cosine(4.0)
+            }
+        };
+        self.push(Box::new(
+            FlipFace::new(Box::new(
+                Rect::new(
+                    213.0, 343.0, 227.0, 332.0, 554.0,RectAxisType::XZ,
+                    Arc::new(DiffuseLight::blackbody(2700.0, 15.0).one_sided().with_profile(profile)),
+                )
+            ))
+        ));
+        //昼光色の小さな球 縁ほど暗くなる配光
+        self.push(Box::new(Sphere::new(
+            Vec3::new(400.0, 90.0, 150.0),
+            40.0,
+            Arc::new(DiffuseLight::blackbody(6500.0, 8.0).with_profile(EmissionProfile::Cosine(2.0))),
+        )));
+
+        self.push(Box::new(FlipFace::new(Box::new(Rect::new(
+            0.0, 555.0, 0.0, 555.0, 555.0, RectAxisType::YZ,
+            Arc::new(Lambertian::new(Box::new(ColorTexture::new(green)))),
+        )))));
+        self.push(Box::new(Rect::new(
+            0.0, 555.0, 0.0, 555.0, 0.0, RectAxisType::YZ,
+            Arc::new(Lambertian::new(Box::new(ColorTexture::new(red)))),
+        )));
+        self.push(Box::new(FlipFace::new(Box::new(Rect::new(
+            0.0, 555.0, 0.0, 555.0, 555.0, RectAxisType::XZ,
+            Arc::new(Lambertian::new(Box::new(ColorTexture::new(white)))),
+        )))));
+        self.push(Box::new(Rect::new(
+            0.0, 555.0, 0.0, 555.0, 0.0, RectAxisType::XZ,
+            Arc::new(Lambertian::new(Box::new(ColorTexture::new(white)))),
+        )));
+        self.push(Box::new(FlipFace::new(Box::new(Rect::new(
+            0.0, 555.0, 0.0, 555.0, 555.0, RectAxisType::XY,
+            Arc::new(Lambertian::new(Box::new(ColorTexture::new(white)))),
+        )))));
+        self.push(Box::new(RectAngle::new(
+            Vec3::new(130.0, 0.0, 65.0),
+            Vec3::new(295.0, 165.0, 230.0),
+            Arc::new(Lambertian::new(Box::new(ColorTexture::new(white)))),
+        )));
+
+        // cornelbox用カメラ
+        let lookfrom = Vec3::new(278.0, 278.0, -800.0);
+        let lookat = Vec3::new(278.0, 278.0, 0.0);
+        let vup = Vec3::new(0.0, 1.0, 0.0);
+
+        let dist_to_focus = (lookfrom - lookat).length().sqrt();
+        let aperture = 0.1;
+
+        Camera::new(
             lookfrom,
             lookat,
             vup,
@@ -441,7 +834,7 @@ impl ShapeList {
             SQUARE_ASPECT,
             aperture,
             dist_to_focus,
-        );
+        )
     }
 
     pub fn random_scene(&mut self) -> Camera {
@@ -518,7 +911,7 @@ impl ShapeList {
         let dist_to_focus = (lookfrom - lookat).length().sqrt();
         let aperture = 0.1;
 
-        return Camera::new(
+        Camera::new(
             lookfrom,
             lookat,
             vup,
@@ -526,6 +919,6 @@ impl ShapeList {
             WIDE_ASPECT,
             aperture,
             dist_to_focus,
-        );
+        )
     }
 }
@@ -0,0 +1,252 @@
+use crate::raymod::*;
+
+use std::f64::consts::PI;
+
+//Preethamの解析的昼光モデル(A Practical Analytic Model for Daylight, 1999)
+//輝度はkcd/m^2 をSKY_SCALE倍したものを放射輝度として扱う
+const SKY_SCALE: f64 = 0.05;
+//大気圏外の太陽の輝度(kcd/m^2)
+const SUN_LUMINANCE: f64 = 1.6e6;
+//太陽の視半径(0.267度)
+const SUN_ANGULAR_RADIUS: f64 = 0.004660;
+
+//Perez関数の係数
+#[derive(Clone, Copy)]
+struct Perez {
+    a: f64,
+    b: f64,
+    c: f64,
+    d: f64,
+    e: f64,
+}
+
+impl Perez {
+    fn f(&self, cos_theta: f64, gamma: f64) -> f64 {
+        (1.0 + self.a * (self.b / cos_theta).exp())
+            * (1.0 + self.c * (self.d * gamma).exp() + self.e * gamma.cos().powi(2))
+    }
+}
+
+fn xyz_to_rgb(x: f64, y: f64, z: f64) -> Color {
+    Color::new(
+        3.2406 * x - 1.5372 * y - 0.4986 * z,
+        -0.9689 * x + 1.8758 * y + 0.0415 * z,
+        0.0557 * x - 0.2040 * y + 1.0570 * z,
+    )
+}
+
+//太陽光の大気透過率 レイリー散乱とエアロゾル散乱のみ、波長はμm
+fn sun_transmittance(theta: f64, turbidity: f64, lambda: f64) -> f64 {
+    let theta_deg = theta.to_degrees();
+    let m = 1.0 / (theta.cos() + 0.15 * (93.885 - theta_deg).powf(-1.253));
+    let beta = 0.04608 * turbidity - 0.04586;
+    let rayleigh = (-m * 0.008735 * lambda.powf(-4.08)).exp();
+    let aerosol = (-m * beta * lambda.powf(-1.3)).exp();
+    rayleigh * aerosol
+}
+
+//空のドーム部分(太陽円盤は含まない)
+pub struct PreethamSky {
+    sun_dir: Vec3,
+    perez_y: Perez,
+    perez_x: Perez,
+    perez_yy: Perez,
+    zenith: (f64, f64, f64),
+    ground: Color,
+}
+
+impl PreethamSky {
+    pub fn new(sun_dir: Vec3, turbidity: f64, ground_albedo: f64) -> Self {
+        let t = turbidity;
+        let sun_dir = sun_dir.norm();
+        //太陽が地平線下だと式が破綻するので天頂角を制限する
+        let theta_s = sun_dir.y.clamp(-1.0, 1.0).acos().min(PI / 2.0 - 0.01);
+        let perez_y = Perez {
+            a: 0.1787 * t - 1.4630,
+            b: -0.3554 * t + 0.4275,
+            c: -0.0227 * t + 5.3251,
+            d: 0.1206 * t - 2.5771,
+            e: -0.0670 * t + 0.3703,
+        };
+        let perez_x = Perez {
+            a: -0.0193 * t - 0.2592,
+            b: -0.0665 * t + 0.0008,
+            c: -0.0004 * t + 0.2125,
+            d: -0.0641 * t - 0.8989,
+            e: -0.0033 * t + 0.0452,
+        };
+        let perez_yy = Perez {
+            a: -0.0167 * t - 0.2608,
+            b: -0.0950 * t + 0.0092,
+            c: -0.0079 * t + 0.2102,
+            d: -0.0441 * t - 1.6537,
+            e: -0.0109 * t + 0.0529,
+        };
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zy = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (th, th2, th3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zx = t * t * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * th)
+            + t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * th + 0.00394)
+            + (0.11693 * th3 - 0.21196 * th2 + 0.06052 * th + 0.25886);
+        let zyy = t * t * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * th)
+            + t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * th + 0.00516)
+            + (0.15346 * th3 - 0.26756 * th2 + 0.06670 * th + 0.26688);
+        //天頂での値をF(0,θs)で割っておく
+        let zenith = (
+            zy / perez_y.f(1.0, theta_s),
+            zx / perez_x.f(1.0, theta_s),
+            zyy / perez_yy.f(1.0, theta_s),
+        );
+        let mut sky = Self {
+            sun_dir,
+            perez_y,
+            perez_x,
+            perez_yy,
+            zenith,
+            ground: Color::zero(),
+        };
+        sky.ground = sky.ground_radiance(turbidity, ground_albedo);
+        sky
+    }
+
+    fn sky_radiance(&self, d: Vec3) -> Color {
+        //地平線付近の発散を避ける
+        let cos_theta = d.y.max(0.01);
+        let gamma = d.dot(&self.sun_dir).clamp(-1.0, 1.0).acos();
+        let yy = self.zenith.0 * self.perez_y.f(cos_theta, gamma);
+        let x = self.zenith.1 * self.perez_x.f(cos_theta, gamma);
+        let y = self.zenith.2 * self.perez_yy.f(cos_theta, gamma);
+        let c = xyz_to_rgb(x / y * yy, yy, (1.0 - x - y) / y * yy) * SKY_SCALE;
+        Color::new(c.x.max(0.0), c.y.max(0.0), c.z.max(0.0))
+    }
+
+    //地面は完全拡散面として、空と太陽からの照度に反射率を掛ける
+    fn ground_radiance(&self, turbidity: f64, albedo: f64) -> Color {
+        const N: usize = 32;
+        let mut irradiance = Color::zero();
+        for i in 0..N {
+            for j in 0..2 * N {
+                let theta = (i as f64 + 0.5) / N as f64 * PI / 2.0;
+                let phi = (j as f64 + 0.5) / (2 * N) as f64 * 2.0 * PI;
+                let d = Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+                let d_omega = theta.sin() * (PI / 2.0 / N as f64) * (PI / N as f64);
+                irradiance = irradiance + self.sky_radiance(d) * (theta.cos() * d_omega);
+            }
+        }
+        let sun = SunDisk::new(self.sun_dir, turbidity);
+        irradiance = irradiance + sun.radiance_center() * (sun.solid_angle() * self.sun_dir.y.max(0.0));
+        irradiance * (albedo / PI)
+    }
+}
+
+impl EnvLight for PreethamSky {
+    fn radiance(&self, d: Vec3) -> Color {
+        let d = d.norm();
+        if d.y < 0.0 { self.ground } else { self.sky_radiance(d) }
+    }
+}
+
+//太陽円盤 立体角内を一様にサンプリングできる
+pub struct SunDisk {
+    dir: Vec3,
+    cos_max: f64,
+    radiance: Color,
+}
+
+impl SunDisk {
+    pub fn new(dir: Vec3, turbidity: f64) -> Self {
+        let dir = dir.norm();
+        let theta = dir.y.clamp(-1.0, 1.0).acos();
+        let radiance = if dir.y <= 0.0 {
+            Color::zero()
+        } else {
+            //赤 緑 青の代表波長
+            let tr = Color::new(
+                sun_transmittance(theta, turbidity, 0.680),
+                sun_transmittance(theta, turbidity, 0.550),
+                sun_transmittance(theta, turbidity, 0.440),
+            );
+            tr * (SUN_LUMINANCE * SKY_SCALE)
+        };
+        Self {
+            dir,
+            cos_max: SUN_ANGULAR_RADIUS.cos(),
+            radiance,
+        }
+    }
+    pub fn solid_angle(&self) -> f64 {
+        2.0 * PI * (1.0 - self.cos_max)
+    }
+    fn radiance_center(&self) -> Color {
+        self.radiance
+    }
+}
+
+impl EnvLight for SunDisk {
+    fn radiance(&self, d: Vec3) -> Color {
+        if d.norm().dot(&self.dir) >= self.cos_max {
+            self.radiance
+        } else {
+            Color::zero()
+        }
+    }
+    fn sample(&self) -> Option<EnvSample> {
+        if self.radiance.is_black() {
+            return None;
+        }
+        let (u, v) = random_2d();
+        let cos_theta = 1.0 - u * (1.0 - self.cos_max);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * v;
+        let (t, b) = self.dir.orthonormal_basis();
+        let wi = t * (sin_theta * phi.cos()) + b * (sin_theta * phi.sin()) + self.dir * cos_theta;
+        Some(EnvSample {
+            wi,
+            li: self.radiance,
+            pdf: 1.0 / self.solid_angle(),
+        })
+    }
+    fn pdf(&self, d: Vec3) -> f64 {
+        if d.norm().dot(&self.dir) >= self.cos_max {
+            1.0 / self.solid_angle()
+        } else {
+            0.0
+        }
+    }
+}
+
+//空と太陽を合わせた昼光 直接光サンプリングは太陽だけ行う
+pub struct PhysicalSky {
+    pub sky: PreethamSky,
+    pub sun: SunDisk,
+    pub intensity: f64,
+}
+
+impl PhysicalSky {
+    //elevation,azimuthは度 azimuthは+xから+z方向
+    pub fn new(elevation: f64, azimuth: f64, turbidity: f64, ground_albedo: f64) -> Self {
+        let (el, az) = (elevation.to_radians(), azimuth.to_radians());
+        let sun_dir = Vec3::new(el.cos() * az.cos(), el.sin(), el.cos() * az.sin());
+        Self {
+            sky: PreethamSky::new(sun_dir, turbidity, ground_albedo),
+            sun: SunDisk::new(sun_dir, turbidity),
+            intensity: 1.0,
+        }
+    }
+    pub fn with_intensity(self, intensity: f64) -> Self {
+        Self { intensity, ..self }
+    }
+}
+
+impl EnvLight for PhysicalSky {
+    fn radiance(&self, d: Vec3) -> Color {
+        (self.sky.radiance(d) + self.sun.radiance(d)) * self.intensity
+    }
+    fn sample(&self) -> Option<EnvSample> {
+        let s = self.sun.sample()?;
+        Some(EnvSample { li: self.radiance(s.wi), ..s })
+    }
+    fn pdf(&self, d: Vec3) -> f64 {
+        self.sun.pdf(d)
+    }
+}
@@ -0,0 +1,308 @@
+use crate::raymod::*;
+
+use rayon::prelude::*;
+use std::f64::consts::PI;
+use std::ops::Range;
+
+//確率的プログレッシブフォトンマッピング(Hachisuka & Jensen 2009, PBRT 3版16.2)
+//カメラから鏡面をたどって最初の拡散面に可視点を置き、光源から飛ばしたフォトンを半径を縮めながら集める
+//直接光は可視点までの各点で次イベント推定し、フォトンは一回以上反射したものだけを数える
+//環境光と平行光源はフォトンを出せないので直接光だけになる
+
+//半径の縮め方 大きいほど速く縮む
+const ALPHA: f64 = 2.0 / 3.0;
+
+//乱数列の種類 カメラ側とフォトンで別の列を使う
+const SPPM_CAMERA: u64 = 0;
+const SPPM_PHOTON: u64 = 1;
+
+//一つのタスクで続けて飛ばすフォトンの数 塊ごとの和を塊の順に足すので、スレッドの数や順によらず同じ値になる
+//放射束はシーンの大きさや光源の面積で大きくなるので、固定小数点でなくf64のまま足す
+const PHOTON_CHUNK: usize = 1024;
+
+struct VisiblePoint {
+    hit: HitInfo,
+    ray: Ray,
+    beta: Color,
+}
+
+struct SppmPixel {
+    radius: f64,
+    n: f64,
+    tau: Color,
+    ld: Color,
+    vp: Option<VisiblePoint>,
+}
+
+struct TreeNode {
+    bbox: AABB,
+    start: usize,
+    end: usize,
+    children: Option<(usize, usize)>,
+}
+
+//可視点の球(中心,半径)を入れたkd木 フォトンの位置を含む球をすべて探す
+struct PointTree {
+    items: Vec<(usize, Vec3, f64)>,
+    nodes: Vec<TreeNode>,
+}
+
+impl PointTree {
+    const LEAF_SIZE: usize = 4;
+
+    fn new(items: Vec<(usize, Vec3, f64)>) -> Self {
+        let n = items.len();
+        let mut tree = Self { items, nodes: Vec::new() };
+        if n > 0 {
+            tree.build(0, n);
+        }
+        tree
+    }
+
+    //子を先に積むので根は最後の節点になる
+    fn build(&mut self, start: usize, end: usize) -> usize {
+        let items = &mut self.items[start..end];
+        let r = |x: f64| Vec3::new(x, x, x);
+        let bbox = items
+            .iter()
+            .map(|&(_, p, radius)| AABB::new(p - r(radius), p + r(radius)))
+            .reduce(|a, b| surrounding_box(&a, &b))
+            .unwrap();
+        let children = if items.len() <= Self::LEAF_SIZE {
+            None
+        } else {
+            let centers = LightBounds::enclose(items.iter().map(|&(_, p, _)| p));
+            let extent = centers.max - centers.min;
+            let axis = if extent.x >= extent.y && extent.x >= extent.z {
+                0
+            } else if extent.y >= extent.z {
+                1
+            } else {
+                2
+            };
+            items.sort_unstable_by(|a, b| a.1[axis].partial_cmp(&b.1[axis]).unwrap());
+            let mid = start + items.len() / 2;
+            Some((self.build(start, mid), self.build(mid, end)))
+        };
+        self.nodes.push(TreeNode { bbox, start, end, children });
+        self.nodes.len() - 1
+    }
+
+    fn query(&self, p: Vec3, mut f: impl FnMut(usize)) {
+        let Some(root) = self.nodes.len().checked_sub(1) else {
+            return;
+        };
+        let mut stack = vec![root];
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            let b = &node.bbox;
+            if (0..3).any(|a| p[a] < b.min[a] || p[a] > b.max[a]) {
+                continue;
+            }
+            match node.children {
+                Some((l, r)) => {
+                    stack.push(l);
+                    stack.push(r);
+                }
+                None => {
+                    for &(idx, c, radius) in &self.items[node.start..node.end] {
+                        if (p - c).length() <= radius * radius {
+                            f(idx);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct Sppm<'a> {
+    world: &'a dyn Shape,
+    env: &'a dyn EnvLight,
+    lights: &'a LightSampler,
+    max_depth: usize,
+    //一回の反復で飛ばすフォトン数
+    photons: usize,
+    //最初の半径 Noneなら可視点でのピクセルの広がりの2倍
+    radius: Option<f64>,
+}
+
+impl<'a> Sppm<'a> {
+    pub fn new(world: &'a dyn Shape, env: &'a dyn EnvLight, lights: &'a LightSampler, max_depth: i64) -> Self {
+        Self {
+            world,
+            env,
+            lights,
+            max_depth: max_depth.max(1) as usize,
+            photons: 0,
+            radius: None,
+        }
+    }
+    pub fn with_photons(self, photons: usize) -> Self {
+        Self { photons, ..self }
+    }
+    pub fn with_radius(self, radius: Option<f64>) -> Self {
+        Self { radius, ..self }
+    }
+
+    //カメラから最初の拡散面まで追って可視点を置く 途中の直接光はldに足す
+    fn camera_pass(&self, pixel: &mut SppmPixel, ray: Ray) {
+        let mut ray = ray;
+        let mut beta = Color::new(1.0, 1.0, 1.0);
+        for _ in 0..self.max_depth {
+            let Some(mut hit) = self.world.hit(&ray, EPS, f64::MAX) else {
+                pixel.ld = pixel.ld + beta.mult(self.env.radiance(ray.d));
+                return;
+            };
+            hit.footprint = ray.cone_width(hit.t);
+            //ここまで鏡面だけなので光源の発光はそのまま足す
+            pixel.ld = pixel.ld + beta.mult(hit.m.emitted(&ray, &hit));
+            let Some(scatter) = hit.m.scatter(&ray, &hit) else {
+                return;
+            };
+            if !scatter.is_specular() {
+                let direct = sample_env(&ray, &hit, self.world, self.env)
+                    + sample_lights(&ray, &hit, self.world, self.lights);
+                pixel.ld = pixel.ld + beta.mult(direct);
+                if pixel.radius == 0.0 {
+                    pixel.radius = self.radius.unwrap_or(2.0 * hit.footprint).max(EPS10);
+                }
+                pixel.vp = Some(VisiblePoint { hit, ray, beta });
+                return;
+            }
+            beta = beta.mult(scatter.albedo);
+            if beta.is_black() {
+                return;
+            }
+            ray = scatter.ray.with_cone(hit.footprint, ray.spread);
+        }
+    }
+
+    //フォトンを一つ飛ばして、当たった点の近くの可視点への放射束を(画素,放射束)で並べる
+    fn trace_photon(&self, pixels: &[SppmPixel], tree: &PointTree, out: &mut Vec<(usize, Color)>) {
+        let Some((light, pmf)) = self.lights.sample_power(random()) else {
+            return;
+        };
+        let Some(le) = light.sample_le() else {
+            return;
+        };
+        if le.pdf_pos <= 0.0 || le.pdf_dir <= 0.0 || le.le.is_black() {
+            return;
+        }
+        let cos = le.n.map_or(1.0, |n| n.dot(&le.ray.d).abs());
+        let mut beta = le.le * (cos / (pmf * le.pdf_pos * le.pdf_dir));
+        let mut ray = le.ray;
+        for depth in 0..self.max_depth {
+            let Some(hit) = self.world.hit(&ray, EPS, f64::MAX) else {
+                return;
+            };
+            if depth > 0 {
+                let wi = -ray.d.norm();
+                tree.query(hit.p, |i| {
+                    let vp = pixels[i].vp.as_ref().unwrap();
+                    let cos = vp.hit.n.dot(&wi).abs();
+                    if cos < EPS {
+                        return;
+                    }
+                    //evalはcosを含むので割ってBSDFだけにする
+                    let f = vp.hit.m.eval(&vp.ray, &vp.hit, wi) / cos;
+                    out.push((i, beta.mult(f)));
+                });
+            }
+            let Some(scatter) = hit.m.scatter(&ray, &hit) else {
+                return;
+            };
+            //暗くなった分だけ確率的に打ち切る
+            let next = beta.mult(scatter.albedo);
+            let q = (1.0 - next.luminance() / beta.luminance().max(EPS)).max(0.0);
+            if random() < q {
+                return;
+            }
+            beta = next / (1.0 - q);
+            ray = scatter.ray;
+        }
+    }
+
+    //塊の中のフォトンを順に飛ばし、画素ごとに(画素,放射束の和,フォトン数)にまとめる
+    fn trace_chunk(&self, pixels: &[SppmPixel], tree: &PointTree, iter: usize, photons: Range<usize>) -> Vec<(usize, Color, u64)> {
+        let mut found = Vec::new();
+        for p in photons {
+            start_stream(&[SPPM_PHOTON, iter as u64, p as u64]);
+            self.trace_photon(pixels, tree, &mut found);
+        }
+        //安定ソートなので同じ画素の寄与は飛ばした順のまま足される
+        found.sort_by_key(|&(i, _)| i);
+        let mut sums: Vec<(usize, Color, u64)> = Vec::new();
+        for (i, c) in found {
+            match sums.last_mut() {
+                Some(last) if last.0 == i => {
+                    last.1 = last.1 + c;
+                    last.2 += 1;
+                }
+                _ => sums.push((i, c, 1)),
+            }
+        }
+        sums
+    }
+
+    //iterations回の反復で画像を作る
+    pub fn render(&self, cam: &Camera, width: usize, height: usize, iterations: usize) -> Vec<Color> {
+        let spread = cam.pixel_spread(height);
+        let photons = if self.photons > 0 { self.photons } else { width * height };
+        let mut pixels: Vec<SppmPixel> = (0..width * height)
+            .map(|_| SppmPixel { radius: 0.0, n: 0.0, tau: Color::zero(), ld: Color::zero(), vp: None })
+            .collect();
+        for iter in 0..iterations {
+            pixels.par_iter_mut().enumerate().for_each(|(i, pixel)| {
+                start_stream(&[SPPM_CAMERA, iter as u64, i as u64]);
+                let (x, y) = (i % width, i / width);
+                let u = (x as f64 + random()) / width as f64;
+                let v = (y as f64 + random()) / height as f64;
+                pixel.vp = None;
+                self.camera_pass(pixel, cam.get_ray(u, v).with_cone(0.0, spread));
+            });
+
+            let items = pixels
+                .iter()
+                .enumerate()
+                .filter(|(_, p)| p.vp.is_some())
+                .map(|(i, p)| (i, p.vp.as_ref().unwrap().hit.p, p.radius))
+                .collect();
+            let tree = PointTree::new(items);
+            let chunks: Vec<_> = (0..photons.div_ceil(PHOTON_CHUNK))
+                .into_par_iter()
+                .map(|k| self.trace_chunk(&pixels, &tree, iter, k * PHOTON_CHUNK..((k + 1) * PHOTON_CHUNK).min(photons)))
+                .collect();
+            let mut phi = vec![Color::zero(); width * height];
+            let mut count = vec![0u64; width * height];
+            for (i, c, m) in chunks.into_iter().flatten() {
+                phi[i] = phi[i] + c;
+                count[i] += m;
+            }
+
+            //集まったフォトン数に応じて半径を縮め、放射束を引き継ぐ
+            pixels.par_iter_mut().enumerate().for_each(|(i, pixel)| {
+                let m = count[i] as f64;
+                if let Some(vp) = &pixel.vp
+                    && m > 0.0
+                {
+                    let n = pixel.n + ALPHA * m;
+                    let radius = pixel.radius * (n / (pixel.n + m)).sqrt();
+                    let scale = (radius * radius) / (pixel.radius * pixel.radius);
+                    pixel.tau = (pixel.tau + vp.beta.mult(phi[i])) * scale;
+                    pixel.n = n;
+                    pixel.radius = radius;
+                }
+            });
+            println!("sppm iteration {}/{}", iter + 1, iterations);
+        }
+        let np = (iterations * photons) as f64;
+        pixels
+            .iter()
+            .map(|p| {
+                let indirect = if p.radius > 0.0 { p.tau / (np * PI * p.radius * p.radius) } else { Color::zero() };
+                p.ld / iterations as f64 + indirect
+            })
+            .collect()
+    }
+}
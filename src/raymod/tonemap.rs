@@ -0,0 +1,215 @@
+use crate::raymod::*;
+
+use std::str::FromStr;
+
+//放射輝度を0..1の表示用の値に圧縮する方法
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMapper {
+    //1を超えた分は切り捨てる
+    Clamp,
+    //L/(1+L)
+    Reinhard,
+    //白点で1になるReinhard
+    ExtendedReinhard,
+    //Uncharted 2のフィルミックカーブ(Hable 2010)
+    Hable,
+    //ACES RRT+ODTの近似(Stephen Hill)
+    Aces,
+    //AgX(Sobotka) 多項式近似版
+    Agx,
+}
+
+impl FromStr for ToneMapper {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(ToneMapper::Clamp),
+            "reinhard" => Ok(ToneMapper::Reinhard),
+            "reinhard-ext" => Ok(ToneMapper::ExtendedReinhard),
+            "hable" => Ok(ToneMapper::Hable),
+            "aces" => Ok(ToneMapper::Aces),
+            "agx" => Ok(ToneMapper::Agx),
+            _ => Err(format!("unknown tone mapper: {} (clamp|reinhard|reinhard-ext|hable|aces|agx)", s)),
+        }
+    }
+}
+
+//行ごとに並べた3x3行列を掛ける
+fn mat3(m: &[[f64; 3]; 3], c: Color) -> Color {
+    Color::new(
+        m[0][0] * c.x + m[0][1] * c.y + m[0][2] * c.z,
+        m[1][0] * c.x + m[1][1] * c.y + m[1][2] * c.z,
+        m[2][0] * c.x + m[2][1] * c.y + m[2][2] * c.z,
+    )
+}
+
+fn map3(c: Color, f: impl Fn(f64) -> f64) -> Color {
+    Color::new(f(c.x), f(c.y), f(c.z))
+}
+
+fn hable_curve(x: f64) -> f64 {
+    const A: f64 = 0.15;
+    const B: f64 = 0.50;
+    const C: f64 = 0.10;
+    const D: f64 = 0.20;
+    const E: f64 = 0.02;
+    const F: f64 = 0.30;
+    ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+}
+
+fn hable(c: Color) -> Color {
+    const WHITE: f64 = 11.2;
+    const EXPOSURE_BIAS: f64 = 2.0;
+    let w = hable_curve(WHITE);
+    map3(c, |x| hable_curve(x * EXPOSURE_BIAS) / w)
+}
+
+fn aces(c: Color) -> Color {
+    //sRGB -> ACES AP1(RRT_SAT込み)
+    const INPUT: [[f64; 3]; 3] = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    //ODT_SAT込み -> sRGB
+    const OUTPUT: [[f64; 3]; 3] = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+    let v = mat3(&INPUT, c);
+    let v = map3(v, |x| (x * (x + 0.0245786) - 0.000090537) / (x * (0.983729 * x + 0.4329510) + 0.238081));
+    map3(mat3(&OUTPUT, v), |x| x.clamp(0.0, 1.0))
+}
+
+fn agx(c: Color) -> Color {
+    const INSET: [[f64; 3]; 3] = [
+        [0.842479062253094, 0.0784335999999992, 0.0792237451477643],
+        [0.0423282422610123, 0.878468636469772, 0.0791661274605434],
+        [0.0423756549057051, 0.0784336, 0.879142973793104],
+    ];
+    const OUTSET: [[f64; 3]; 3] = [
+        [1.19687900512017, -0.0980208811401368, -0.0990297440797205],
+        [-0.0528968517574562, 1.15190312990417, -0.0989611768448433],
+        [-0.0529716355144438, -0.0980434501171241, 1.15107367264116],
+    ];
+    const MIN_EV: f64 = -12.47393;
+    const MAX_EV: f64 = 4.026069;
+    let v = mat3(&INSET, c);
+    //対数で0..1に詰めてからS字のコントラストをかける
+    let v = map3(v, |x| {
+        let x = (x.max(1e-10).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232
+    });
+    //カーブの出力は表示用に符号化済みなのでリニアに戻す
+    map3(mat3(&OUTSET, v), |x| x.max(0.0).powf(2.2))
+}
+
+//露出・ホワイトバランス・トーンマップをこの順にかける sRGBへの符号化は保存時に行う
+#[derive(Debug, Clone, Copy)]
+pub struct ColorPipeline {
+    //露出補正(EV) 1で2倍
+    pub exposure: f64,
+    //照明の色温度(K) この色が白になるよう補正する
+    pub white_balance: Option<f64>,
+    pub tonemap: ToneMapper,
+    //ExtendedReinhardで1になる輝度 Noneなら画像の最大輝度
+    pub white_point: Option<f64>,
+}
+
+impl Default for ColorPipeline {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            white_balance: None,
+            tonemap: ToneMapper::Clamp,
+            white_point: None,
+        }
+    }
+}
+
+impl ColorPipeline {
+    pub fn with_exposure(self, exposure: f64) -> Self {
+        Self { exposure, ..self }
+    }
+    pub fn with_white_balance(self, white_balance: Option<f64>) -> Self {
+        Self { white_balance, ..self }
+    }
+    pub fn with_tonemap(self, tonemap: ToneMapper) -> Self {
+        Self { tonemap, ..self }
+    }
+    pub fn with_white_point(self, white_point: Option<f64>) -> Self {
+        Self { white_point, ..self }
+    }
+
+    //Bradford変換によるvon Kries型の色順応 D65を基準の白にする
+    fn white_balance_gain(kelvin: f64) -> impl Fn(Color) -> Color {
+        const RGB_TO_XYZ: [[f64; 3]; 3] = [
+            [0.4124, 0.3576, 0.1805],
+            [0.2126, 0.7152, 0.0722],
+            [0.0193, 0.1192, 0.9505],
+        ];
+        const XYZ_TO_RGB: [[f64; 3]; 3] = [
+            [3.2406, -1.5372, -0.4986],
+            [-0.9689, 1.8758, 0.0415],
+            [0.0557, -0.2040, 1.0570],
+        ];
+        const BRADFORD: [[f64; 3]; 3] = [
+            [0.8951, 0.2664, -0.1614],
+            [-0.7502, 1.7135, 0.0367],
+            [0.0389, -0.0685, 1.0296],
+        ];
+        const BRADFORD_INV: [[f64; 3]; 3] = [
+            [0.9869929, -0.1470543, 0.1599627],
+            [0.4323053, 0.5183603, 0.0492912],
+            [-0.0085287, 0.0400428, 0.9684867],
+        ];
+        let lms = |c: Color| mat3(&BRADFORD, mat3(&RGB_TO_XYZ, c));
+        //blackbodyはリニアsRGBで負の成分を切っているが、白の向きを決めるだけなので十分
+        let src = lms(blackbody(kelvin));
+        let dst = lms(Color::new(1.0, 1.0, 1.0));
+        let gain = Vec3::new(dst.x / src.x, dst.y / src.y, dst.z / src.z);
+        move |c| mat3(&XYZ_TO_RGB, mat3(&BRADFORD_INV, lms(c).mult(gain)))
+    }
+
+    //画像全体に適用する 結果は0..1のリニア値
+    pub fn apply(&self, image: &[Color]) -> Vec<Color> {
+        let scale = 2f64.powf(self.exposure);
+        let balance = self.white_balance.map(Self::white_balance_gain);
+        let image: Vec<Color> = image
+            .iter()
+            .map(|&c| {
+                let c = c * scale;
+                let c = match &balance {
+                    Some(f) => f(c),
+                    None => c,
+                };
+                map3(c, |x| x.max(0.0))
+            })
+            .collect();
+        let white = self
+            .white_point
+            .unwrap_or_else(|| image.iter().map(|c| c.luminance()).fold(0.0, f64::max))
+            .max(EPS);
+        image
+            .iter()
+            .map(|&c| match self.tonemap {
+                ToneMapper::Clamp => c,
+                ToneMapper::Reinhard | ToneMapper::ExtendedReinhard => {
+                    let l = c.luminance();
+                    if l <= 0.0 {
+                        return Color::zero();
+                    }
+                    let w2 = if self.tonemap == ToneMapper::Reinhard { f64::INFINITY } else { white * white };
+                    c * ((1.0 + l / w2) / (1.0 + l))
+                }
+                ToneMapper::Hable => hable(c),
+                ToneMapper::Aces => aces(c),
+                ToneMapper::Agx => agx(c),
+            })
+            .map(|c| map3(c, |x| x.clamp(0.0, 1.0)))
+            .collect()
+    }
+}
@@ -3,8 +3,16 @@ use std::io::Write;
 use std::ops::{Add, Div, Mul, Neg, Rem, Sub};
 use std::ops::{Index, IndexMut};
 
+use super::sampler::{next_1d, next_2d, next_stream};
+
+//画素を描画中ならサンプラーの次の次元、そうでなければシードから決まる乱数列
 pub fn random() -> f64 {
-    rand::random::<f64>()
+    next_1d().unwrap_or_else(next_stream)
+}
+
+//組で使う2つの乱数 サンプラーの2次元の組から取る
+pub fn random_2d() -> (f64, f64) {
+    next_2d().unwrap_or_else(|| (next_stream(), next_stream()))
 }
 
 pub fn random_range(a: f64, b: f64) -> f64 {
@@ -43,42 +51,52 @@ impl Vec3 {
     }
     pub fn norm(mut self) -> Vec3 {
         let l = 1.0 / (self.x * self.x + self.y * self.y + self.z * self.z).sqrt();
-        self.x = self.x * l;
-        self.y = self.y * l;
-        self.z = self.z * l;
+        self.x *= l;
+        self.y *= l;
+        self.z *= l;
         self
     }
     pub fn dot(&self, b: &Vec3) -> f64 {
-        return self.x * b.x + self.y * b.y + self.z * b.z;
+        self.x * b.x + self.y * b.y + self.z * b.z
     }
     pub fn length(&self) -> f64 {
-        return self.x * self.x + self.y * self.y + self.z * self.z;
+        self.x * self.x + self.y * self.y + self.z * self.z
     }
     pub fn random() -> Vec3 {
-        return Vec3::new(random(), random(), random());
+        Vec3::new(random(), random(), random())
     }
     pub fn random_full() -> Vec3 {
         let x = random();
-        return Vec3::new(x, x, x);
+        Vec3::new(x, x, x)
     }
     pub fn vec3_random_range(a: f64, b: f64) -> Vec3 {
-        return Vec3::new(random_range(a, b), random_range(a, b), random_range(a, b));
+        Vec3::new(random_range(a, b), random_range(a, b), random_range(a, b))
+    }
+    //単位球面上の一様な方向
+    pub fn random_unit() -> Vec3 {
+        let (u, v) = random_2d();
+        let z = 1.0 - 2.0 * u;
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * std::f64::consts::PI * v;
+        Vec3::new(r * phi.cos(), r * phi.sin(), z)
     }
+    //単位球の内部の一様な点 棄却法だとサンプラーの次元がずれるので直接作る
     pub fn random_hemisphere() -> Vec3 {
-        loop {
-            let point = Vec3::vec3_random_range(-1.0, 1.0);
-            if point.length() < 1.0 {
-                return point;
-            }
-        }
+        Vec3::random_unit() * random().cbrt()
     }
+    //同心円写像(Shirley-Chiu)で単位円板へ 層化の構造が保たれる
     pub fn random_in_unit_disk() -> Vec3 {
-        loop {
-            let p = Vec3::new(random_range(-1.0, 1.0), random_range(-1.0, 1.0), 0.0);
-            if p.length() < 1.0 {
-                return p;
-            }
+        let (u, v) = random_2d();
+        let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+        if a == 0.0 && b == 0.0 {
+            return Vec3::zero();
         }
+        let (r, theta) = if a.abs() > b.abs() {
+            (a, std::f64::consts::FRAC_PI_4 * (b / a))
+        } else {
+            (b, std::f64::consts::FRAC_PI_2 - std::f64::consts::FRAC_PI_4 * (a / b))
+        };
+        Vec3::new(r * theta.cos(), r * theta.sin(), 0.0)
     }
     pub fn reflect(&self, normal: Vec3) -> Vec3 {
         *self - normal * 2.0 * self.dot(&normal)
@@ -88,11 +106,30 @@ impl Vec3 {
         let dt = uv.dot(&normal);
         let d = 1.0 - ni_over_nt * ni_over_nt * (1.0 - dt * dt);
         if d > 0.0 {
-            Some((uv - normal * dt) * (-1.0 * ni_over_nt) - normal * d.sqrt())
+            Some((uv - normal * dt) * -ni_over_nt - normal * d.sqrt())
         } else {
             None
         }
     }
+    // 自分を法線とする正規直交基底(接線,従法線)を返す
+    pub fn orthonormal_basis(&self) -> (Vec3, Vec3) {
+        let a = if self.x.abs() > 0.9 { Vec3::yaxis() } else { Vec3::xaxis() };
+        let t = (a % *self).norm();
+        let b = *self % t;
+        (t, b)
+    }
+    pub fn is_finite(&self) -> bool {
+        self.x.is_finite() && self.y.is_finite() && self.z.is_finite()
+    }
+    pub fn is_black(&self) -> bool {
+        self.x <= 0.0 && self.y <= 0.0 && self.z <= 0.0
+    }
+    pub fn max_element(&self) -> f64 {
+        self.x.max(self.y).max(self.z)
+    }
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
     pub fn from_rgb(r: u8, g: u8, b: u8) -> Self {
         Self::new(r as f64 / 255.0, g as f64 / 255.0, b as f64 / 255.0)
     }
@@ -183,32 +220,38 @@ impl IndexMut<usize> for Vec3 {
     }
 }
 
-fn clamp(x: f64) -> f64 {
-    if x < 0.0 {
-        0.0
-    } else if x > 1.0 {
-        1.0
+//sRGBの8bit値をリニアに戻す
+pub fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+//リニアをsRGBの値に符号化する(OETF) 暗部は直線
+pub fn linear_to_srgb(c: f64) -> f64 {
+    if c <= 0.0031308 {
+        c * 12.92
     } else {
-        x
+        1.055 * c.powf(1.0 / 2.4) - 0.055
     }
 }
 
+fn clamp(x: f64) -> f64 {
+    x.clamp(0.0, 1.0)
+}
+
 fn to_int(x: f64) -> u8 {
-    (clamp(x).powf(1.0 / 2.2) * 255.0 + 0.5) as u8
+    (linear_to_srgb(clamp(x)) * 255.0 + 0.5) as u8
 }
 
 #[allow(dead_code)]
 fn save_ppm_file(filename: &str, image: Vec<Color>, width: usize, height: usize) {
     let mut f = fs::File::create(filename).unwrap();
     writeln!(f, "P3\n{} {}\n{}", width, height, 255).unwrap();
-    for i in 0..(width * (height)) {
-        write!(
-            f,
-            "{} {} {} ",
-            to_int(image[i as usize].x),
-            to_int(image[i as usize].y),
-            to_int(image[i as usize].z)
-        )
+    for c in image.iter().take(width * height) {
+        write!(f, "{} {} {} ", to_int(c.x), to_int(c.y), to_int(c.z))
         .unwrap();
     }
 }
@@ -228,3 +271,14 @@ pub fn save_png_file(filename: &str, out_image: Vec<Color>, width: usize, height
     // Save the image as “fractal.png”, the format is deduced from the path
     imgbuf.save(filename).unwrap();
 }
+
+//法線や深度などのデータ用 ガンマをかけずに0..1をそのまま8bitにする
+pub fn save_png_raw(filename: &str, out_image: &[Color], width: usize, height: usize) {
+    let mut imgbuf = image::ImageBuffer::new(width as u32, height as u32);
+    let to_u8 = |x: f64| (clamp(x) * 255.0 + 0.5) as u8;
+    for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
+        let c = out_image[(x as usize) + (y as usize) * width];
+        *pixel = image::Rgb([to_u8(c.x), to_u8(c.y), to_u8(c.z)]);
+    }
+    imgbuf.save(filename).unwrap();
+}
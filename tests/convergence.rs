@@ -0,0 +1,45 @@
+//浅い経路に限れば、パストレーシングとBDPTは同じ明るさに収束することを確かめる
+use std::fs;
+use std::process::{Command, Stdio};
+
+//コーネルボックスを描いて、画像全体の平均輝度を返す
+fn mean_luminance(name: &str, args: &[&str]) -> f64 {
+    let out = std::env::temp_dir().join(format!("rr_convergence_{}_{}.pfm", std::process::id(), name));
+    let status = Command::new(env!("CARGO_BIN_EXE_RustRayNextWeekend"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["-m", "0", "-w", "24", "-s", "64", "--seed", "3"])
+        .args(args)
+        .arg("-o")
+        .arg(&out)
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success(), "render {} failed", name);
+    let bytes = fs::read(&out).unwrap();
+    fs::remove_file(&out).unwrap();
+    //ヘッダは3行 本体はリトルエンディアンのf32のRGB
+    let header = bytes.iter().enumerate().filter(|&(_, &b)| b == b'\n').nth(2).unwrap().0 + 1;
+    let values: Vec<f64> = bytes[header..]
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64)
+        .collect();
+    let sum: f64 = values.chunks_exact(3).map(|c| 0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2]).sum();
+    sum / (values.len() / 3) as f64
+}
+
+fn assert_agree(depth: &str) {
+    let path = mean_luminance(&format!("path{}", depth), &["--integrator", "path", "--max-depth", depth]);
+    let bdpt = mean_luminance(&format!("bdpt{}", depth), &["--integrator", "bdpt", "--max-depth", depth]);
+    let error = (bdpt - path).abs() / path;
+    assert!(error < 0.01, "max depth {}: path {} and bdpt {} differ by {:.2}%", depth, path, bdpt, error * 100.0);
+}
+
+#[test]
+fn bdpt_matches_path_with_direct_light() {
+    assert_agree("1");
+}
+
+#[test]
+fn bdpt_matches_path_with_one_bounce() {
+    assert_agree("2");
+}
@@ -0,0 +1,48 @@
+//同じシードなら、スレッドの数や実行のたびに変わらず同じバイト列の画像になることを確かめる
+use std::fs;
+use std::process::{Command, Stdio};
+
+//小さいコーネルボックスを描いて、線形のままのPFMの中身を返す
+fn render(name: &str, threads: usize, args: &[&str]) -> Vec<u8> {
+    let out = std::env::temp_dir().join(format!("rr_determinism_{}_{}_{}.pfm", std::process::id(), name, threads));
+    let status = Command::new(env!("CARGO_BIN_EXE_RustRayNextWeekend"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .env("RAYON_NUM_THREADS", threads.to_string())
+        .args(["-m", "7", "-w", "24", "-s", "1", "--seed", "7"])
+        .args(args)
+        .arg("-o")
+        .arg(&out)
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success(), "render {} failed", name);
+    let bytes = fs::read(&out).unwrap();
+    fs::remove_file(&out).unwrap();
+    bytes
+}
+
+fn assert_deterministic(name: &str, args: &[&str]) {
+    let single = render(name, 1, args);
+    assert_eq!(single, render(name, 1, args), "{}: two runs differ", name);
+    assert_eq!(single, render(name, 4, args), "{}: 1 and 4 threads differ", name);
+}
+
+#[test]
+fn path_is_deterministic() {
+    assert_deterministic("path", &["--integrator", "path"]);
+}
+
+#[test]
+fn bdpt_is_deterministic() {
+    assert_deterministic("bdpt", &["--integrator", "bdpt", "--filter", "gaussian"]);
+}
+
+#[test]
+fn sppm_is_deterministic() {
+    assert_deterministic("sppm", &["--integrator", "sppm", "--photons", "3000"]);
+}
+
+#[test]
+fn adaptive_is_deterministic() {
+    assert_deterministic("adaptive", &["--adaptive", "0.05", "--max-spp", "16", "--pass-spp", "2"]);
+}